@@ -1,27 +1,57 @@
+pub mod headless;
+
+mod calibration;
+mod filter;
+mod preprocess;
+mod reconstruction;
 mod scan;
 mod scene;
 
-use std::{f32::consts::PI, io, sync::Arc};
+use std::{f32::consts::PI, io, path::PathBuf, sync::Arc};
 
-use iced::{alignment::Vertical, widget::{button, text_input, column, container, row, shader, text}, window, Alignment::Center, Element, Length::{Fill, FillPortion}, Subscription, Task, Theme};
+use filter::RampFilter;
+use futures::{channel::mpsc, future, SinkExt, Stream, StreamExt};
+use iced::{alignment::{Horizontal, Vertical}, widget::{button, center, checkbox, text_input, column, container, mouse_area, opaque, pick_list, progress_bar, row, scrollable, shader, slider, stack, text}, window, Alignment::Center, Color, Element, Length::{Fill, FillPortion}, Subscription, Task, Theme};
 use iced_winit::runtime::Program;
-use scan::CtScan;
+use preprocess::{beam_hardening::BeamHardening, denoise::{DenoiseFilter, DenoiseStep}, ProcessingSettings};
+use reconstruction::{ReconstructionMode, Volume};
+use scan::{CtScan, LoadError, RotationDirection, ScanImage};
 use rfd::AsyncFileDialog;
-use scene::Scene;
+use scene::{Scene, VolumeSource};
+use tokio::task;
 
 #[derive(Debug, Clone)]
 pub enum ScanLoadError {
     NonePicked,
-    FileLoadError(Arc<io::Error>),
+    FileLoadError(Arc<[LoadError]>),
 }
 
 type ScanLoadResult = Result<Arc<CtScan>, ScanLoadError>;
 
+// voxels along each axis of the reconstructed volume that can be picked in the sidebar
+const VOLUME_RESOLUTIONS: [u32; 4] = [64, 128, 192, 256];
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CTRS {
     scan: Option<Arc<CtScan>>,
     scene: Option<Scene>,
     status_message: String,
     threshold: f32,
+    volume_resolution: u32,
+    processing: ProcessingSettings,
+    // aborts the running iterative reconstruction when dropped
+    reconstruction_task: Option<iced::task::Handle>,
+    // detector offset estimated from the current scan, which hasn't been applied yet
+    detector_offset_estimate: Option<f32>,
+    // rotation direction detected from the current scan, if it disagrees with the descriptor
+    detected_direction: Option<RotationDirection>,
+    // changed whenever the scan or its geometry changes, so results for an older geometry are dropped
+    geometry_generation: u64,
+    // aborts loading the scan when dropped, and how many of its images have been loaded out of the total
+    loading_task: Option<iced::task::Handle>,
+    load_progress: Option<(usize, usize)>,
+    // problems with the last scan that couldn't be loaded, shown in a dialog until it is dismissed
+    load_errors: Option<Arc<[LoadError]>>,
 }
 
 impl Default for CTRS {
@@ -31,6 +61,15 @@ impl Default for CTRS {
             scene: Default::default(),
             status_message: String::from("Please open a scan"),
             threshold: 0.71,
+            volume_resolution: 128,
+            processing: Default::default(),
+            reconstruction_task: None,
+            detector_offset_estimate: None,
+            detected_direction: None,
+            geometry_generation: 0,
+            loading_task: None,
+            load_progress: None,
+            load_errors: None,
         }
     }
 }
@@ -41,7 +80,43 @@ pub enum Message {
     HelpPressed,
     ScreenshotPressed,
     ScanLoaded(ScanLoadResult),
+    ProjectionsProcessed(u64, ProcessingSettings, Arc<[ScanImage]>),
+    VolumeReconstructed(u64, ProcessingSettings, u32, Arc<Volume>),
     ThresholdEdited(String),
+    VolumeResolutionSelected(u32),
+    ModeSelected(ReconstructionMode),
+    FilterSelected(RampFilter),
+    CutoffChanged(f32),
+    CutoffReleased,
+    IterationsChanged(u32),
+    RelaxationChanged(f32),
+    SubsetsChanged(u32),
+    TvWeightChanged(f32),
+    MetalThresholdChanged(f32),
+    MetalThresholdReleased,
+    IterativeSettingReleased,
+    NonNegativeToggled(bool),
+    DenoiseFilterSelected(usize, DenoiseFilter),
+    DenoiseSizeChanged(usize, f32),
+    DenoiseSizeReleased,
+    NormalizeIntensityToggled(bool),
+    BeamHardeningSelected(BeamHardening),
+    BeamHardeningStrengthChanged(f32),
+    BeamHardeningStrengthReleased,
+    RemoveRingsToggled(bool),
+    RingRemovalStrengthChanged(u32),
+    RingRemovalStrengthReleased,
+    DetectorOffsetChanged(f32),
+    DetectorOffsetReleased,
+    EstimateDetectorOffsetPressed,
+    DetectorOffsetEstimated(Option<f32>),
+    ApplyDetectorOffsetPressed,
+    RotationDirectionDetected(PathBuf, Option<RotationDirection>),
+    FixRotationDirectionPressed,
+    DescriptorWritten(PathBuf, Result<(), Arc<io::Error>>),
+    ScanLoadProgress(usize, usize),
+    CancelLoadPressed,
+    LoadErrorsDismissed,
     Tick,
 }
 
@@ -55,29 +130,131 @@ impl Program for CTRS {
             Message::OpenPressed => {
                 self.status_message = String::from("Loading scan...");
 
-                Task::perform(load_scan(), Message::ScanLoaded)
+                // opening another scan cancels loading the previous one
+                let (task, handle) = Task::run(load_scan(), |message| message).abortable();
+                self.loading_task = Some(handle.abort_on_drop());
+
+                task
+            },
+            Message::ScanLoadProgress(loaded, total) => {
+                self.load_progress = Some((loaded, total));
+                self.status_message = format!("Loading scan, {loaded}/{total} images...");
+
+                Task::none()
+            },
+            Message::CancelLoadPressed => {
+                self.loading_task = None;
+                self.load_progress = None;
+                self.status_message = String::from("Loading cancelled");
+
+                Task::none()
             },
             Message::HelpPressed => Task::none(),
             Message::ScreenshotPressed => Task::none(),
             Message::ScanLoaded(Ok(scan)) => {
-                self.status_message = format!("Scan {} loaded", scan.name);
-                self.scene = Some(Scene::new(scan.clone(), self.threshold));
-                self.scan = Some(scan);
+                self.loading_task = None;
+                self.load_progress = None;
+                self.scene = None;
+                self.scan = Some(scan.clone());
+                self.geometry_generation += 1;
+                self.detector_offset_estimate = None;
+                self.detected_direction = None;
 
-                log::info!("Updated scan");
-                Task::none()
+                // check the rotation direction in the background, as getting it wrong ruins the reconstruction
+                let detect_direction = Task::perform(
+                    async move {
+                        let direction = task::spawn_blocking({
+                            let scan = scan.clone();
+                            move || calibration::detect_rotation_direction(&scan)
+                        })
+                        .await
+                        .unwrap();
+
+                        (scan.descriptor_path.clone(), direction)
+                    },
+                    |(path, direction)| Message::RotationDirectionDetected(path, direction)
+                );
+
+                Task::batch([self.process_projections(), detect_direction])
             },
-            Message::ScanLoaded(Err(err)) => {// TODO: notify user that error happened
+            Message::ScanLoaded(Err(err)) => {
+                self.loading_task = None;
+                self.load_progress = None;
                 log::error!("Error loading scan: {err:?}");
                 self.status_message = match err {
                     ScanLoadError::NonePicked => String::from("Please pick a file"),
-                    ScanLoadError::FileLoadError(err) => format!("{err}"),
+                    ScanLoadError::FileLoadError(errors) => {
+                        let message = match errors.len() {
+                            1 => String::from("The scan couldn't be loaded"),
+                            n => format!("The scan couldn't be loaded, there are {n} problems"),
+                        };
+                        self.load_errors = Some(errors);
+                        message
+                    },
                 };
 
                 self.scan = None;
 
                 Task::none()
             },
+            Message::LoadErrorsDismissed => {
+                self.load_errors = None;
+
+                Task::none()
+            },
+            Message::ProjectionsProcessed(generation, settings, projection_data) => {
+                // ignore results for settings or a geometry that have since been changed
+                if generation != self.geometry_generation || settings != self.processing {
+                    return Task::none();
+                }
+
+                if let Some(scan) = &self.scan {
+                    self.status_message = format!("Scan {} loaded", scan.name);
+                    self.scene = Some(Scene::new(
+                        scan,
+                        VolumeSource::Projections(projection_data),
+                        settings.mode,
+                        self.volume_resolution,
+                        self.threshold
+                    ));
+
+                    log::info!("Updated scan");
+                }
+
+                Task::none()
+            },
+            Message::VolumeReconstructed(generation, settings, iteration, volume) => {
+                if generation != self.geometry_generation || settings != self.processing {
+                    return Task::none();
+                }
+
+                let Some(scan) = &self.scan else {
+                    return Task::none();
+                };
+
+                // the analytic modes that run on the CPU only yield the finished volume
+                let iterations = if settings.mode.is_iterative() { settings.iterative.iterations } else { 1 };
+                self.status_message = if iteration < iterations {
+                    format!("Reconstructing {}, iteration {iteration}/{}...", scan.name, settings.iterative.iterations)
+                } else {
+                    format!("Scan {} reconstructed", scan.name)
+                };
+
+                // keep the current scene so the view doesn't jump back to the start
+                let source = VolumeSource::Volume(volume);
+                match &mut self.scene {
+                    Some(scene) => scene.set_source(source),
+                    None => self.scene = Some(Scene::new(
+                        scan,
+                        source,
+                        settings.mode,
+                        self.volume_resolution,
+                        self.threshold
+                    )),
+                }
+
+                Task::none()
+            },
             Message::ThresholdEdited(str) => {
                 if let Ok(new) = str.parse::<f32>() {
                     self.threshold = new;
@@ -89,6 +266,198 @@ impl Program for CTRS {
 
                 Task::none()
             },
+            Message::VolumeResolutionSelected(volume_resolution) => {
+                self.volume_resolution = volume_resolution;
+
+                // volumes reconstructed on the CPU have a fixed resolution
+                if self.processing.mode.on_cpu() || self.scene.as_ref().is_some_and(Scene::has_volume) {
+                    return self.process_projections();
+                }
+
+                if let Some(scene) = &mut self.scene {
+                    scene.set_volume_resolution(volume_resolution);
+                }
+
+                Task::none()
+            },
+            Message::ModeSelected(mode) => {
+                self.processing.mode = mode;
+
+                self.process_projections()
+            },
+            Message::FilterSelected(filter) => {
+                self.processing.filter.filter = filter;
+
+                self.process_projections()
+            },
+            Message::CutoffChanged(cutoff) => {
+                self.processing.filter.cutoff = cutoff;
+
+                Task::none()
+            },
+            Message::CutoffReleased => self.process_projections(),
+            Message::IterationsChanged(iterations) => {
+                self.processing.iterative.iterations = iterations;
+
+                Task::none()
+            },
+            Message::RelaxationChanged(relaxation) => {
+                self.processing.iterative.relaxation = relaxation;
+
+                Task::none()
+            },
+            Message::SubsetsChanged(subsets) => {
+                self.processing.iterative.subsets = subsets;
+
+                Task::none()
+            },
+            Message::TvWeightChanged(tv_weight) => {
+                self.processing.iterative.tv_weight = tv_weight;
+
+                Task::none()
+            },
+            Message::IterativeSettingReleased => self.process_projections(),
+            Message::MetalThresholdChanged(threshold) => {
+                self.processing.metal.threshold = threshold;
+
+                Task::none()
+            },
+            Message::MetalThresholdReleased => self.process_projections(),
+            Message::NonNegativeToggled(non_negative) => {
+                self.processing.iterative.non_negative = non_negative;
+
+                self.process_projections()
+            },
+            Message::DenoiseFilterSelected(step, filter) => {
+                let step = &mut self.processing.denoise.steps[step];
+                step.filter = filter;
+                if filter.has_integer_size() {
+                    step.size = step.size.round().max(1.);
+                }
+
+                self.process_projections()
+            },
+            Message::DenoiseSizeChanged(step, size) => {
+                self.processing.denoise.steps[step].size = size;
+
+                Task::none()
+            },
+            Message::DenoiseSizeReleased => self.process_projections(),
+            Message::NormalizeIntensityToggled(normalize_intensity) => {
+                self.processing.corrections.normalize_intensity = normalize_intensity;
+
+                self.process_projections()
+            },
+            Message::BeamHardeningSelected(beam_hardening) => {
+                self.processing.corrections.beam_hardening = beam_hardening;
+
+                self.process_projections()
+            },
+            Message::BeamHardeningStrengthChanged(strength) => {
+                self.processing.corrections.beam_hardening_strength = strength;
+
+                Task::none()
+            },
+            Message::BeamHardeningStrengthReleased => self.process_projections(),
+            Message::RemoveRingsToggled(remove_rings) => {
+                self.processing.corrections.remove_rings = remove_rings;
+
+                self.process_projections()
+            },
+            Message::RingRemovalStrengthChanged(strength) => {
+                self.processing.corrections.ring_removal_strength = strength;
+
+                Task::none()
+            },
+            Message::RingRemovalStrengthReleased => self.process_projections(),
+            Message::DetectorOffsetChanged(detector_offset) => {
+                self.set_detector_offset(detector_offset);
+
+                Task::none()
+            },
+            Message::DetectorOffsetReleased => self.process_projections(),
+            Message::EstimateDetectorOffsetPressed => {
+                let Some(scan) = self.scan.clone() else {
+                    return Task::none();
+                };
+
+                self.status_message = format!("Estimating detector offset of {}...", scan.name);
+
+                Task::perform(
+                    async move {
+                        task::spawn_blocking(move || calibration::estimate_detector_offset(&scan))
+                            .await
+                            .unwrap()
+                    },
+                    Message::DetectorOffsetEstimated
+                )
+            },
+            Message::DetectorOffsetEstimated(estimate) => {
+                self.status_message = match estimate {
+                    Some(estimate) => format!("Estimated detector offset: {estimate:.2} mm"),
+                    None => String::from("Can't estimate the detector offset without projections 180° apart"),
+                };
+                self.detector_offset_estimate = estimate;
+
+                Task::none()
+            },
+            Message::ApplyDetectorOffsetPressed => {
+                let Some(estimate) = self.detector_offset_estimate.take() else {
+                    return Task::none();
+                };
+
+                self.set_detector_offset(estimate);
+
+                self.process_projections()
+            },
+            Message::RotationDirectionDetected(path, direction) => {
+                // ignore results for scans that have since been closed
+                let Some(scan) = self.scan.as_ref().filter(|scan| scan.descriptor_path == path) else {
+                    return Task::none();
+                };
+
+                if let Some(direction) = direction.filter(|direction| *direction != scan.direction) {
+                    log::warn!("The scan seems to rotate {direction:?}, but the descriptor says {:?}", scan.direction);
+                    self.status_message = format!(
+                        "{} seems to rotate {direction:?}, not {:?}, as that reconstructs with fewer negative streaks",
+                        scan.name, scan.direction
+                    );
+                    self.detected_direction = Some(direction);
+                }
+
+                Task::none()
+            },
+            Message::FixRotationDirectionPressed => {
+                let (Some(scan), Some(direction)) = (&mut self.scan, self.detected_direction.take()) else {
+                    return Task::none();
+                };
+
+                let scan = Arc::make_mut(scan);
+                scan.direction = direction;
+                self.geometry_generation += 1;
+
+                let path = scan.descriptor_path.clone();
+                let write_descriptor = Task::perform(
+                    async move {
+                        let result = CtScan::write_direction(&path, direction).await.map_err(Arc::new);
+                        (path, result)
+                    },
+                    |(path, result)| Message::DescriptorWritten(path, result)
+                );
+
+                Task::batch([self.process_projections(), write_descriptor])
+            },
+            Message::DescriptorWritten(path, result) => {
+                self.status_message = match result {
+                    Ok(()) => format!("Fixed the rotation direction in {}", path.display()),
+                    Err(err) => {
+                        log::error!("Error writing {}: {err}", path.display());
+                        format!("Couldn't write {}: {err}", path.display())
+                    },
+                };
+
+                Task::none()
+            },
             Message::Tick => {
                 if let Some(scene) = &mut self.scene {
                     scene.rotate(PI/16.);
@@ -106,7 +475,10 @@ impl Program for CTRS {
             button("Open").on_press(Message::OpenPressed),
             button("Help").on_press(Message::HelpPressed),
             status_message,
-        ].spacing(5).padding(2).align_y(Vertical::Center).height(40).width(Fill);
+        ]
+        .push_maybe(self.load_progress.map(|(loaded, total)| progress_bar(0. ..=total as f32, loaded as f32).width(200).height(20)))
+        .push_maybe(self.loading_task.as_ref().map(|_| button("Cancel").on_press(Message::CancelLoadPressed)))
+        .spacing(5).padding(2).align_y(Vertical::Center).height(40).width(Fill);
 
         let shader_container = container({
             let element: Element<'_, Self::Message, Self::Theme, Self::Renderer> = match &self.scene {
@@ -133,18 +505,231 @@ impl Program for CTRS {
         .width(Fill)
         .align_y(Center);
 
-        let sidebar = container(
-            column![
-                button(
-                    container("Screenshot")
-                        .width(Fill)
-                        .align_x(Center)
-                    )
-                    .on_press(Message::ScreenshotPressed).width(Fill),
-                threshold_input,
-            ]
-            .spacing(5)
-        )
+        let resolution_input = row![
+            text("Voxels: "),
+            pick_list(
+                VOLUME_RESOLUTIONS,
+                Some(self.volume_resolution),
+                Message::VolumeResolutionSelected
+            )
+            .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let mode_input = row![
+            text("Mode: "),
+            pick_list(
+                ReconstructionMode::ALL,
+                Some(self.processing.mode),
+                Message::ModeSelected
+            )
+            .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let filter_input = row![
+            text("Filter: "),
+            pick_list(
+                RampFilter::ALL,
+                Some(self.processing.filter.filter),
+                Message::FilterSelected
+            )
+            .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let cutoff_input = row![
+            text(format!("Cutoff: {:.2} ", self.processing.filter.cutoff)),
+            slider(0.05..=1., self.processing.filter.cutoff, Message::CutoffChanged)
+                .step(0.05)
+                .on_release(Message::CutoffReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let iterations_input = row![
+            text(format!("Iterations: {} ", self.processing.iterative.iterations)),
+            slider(1..=100, self.processing.iterative.iterations, Message::IterationsChanged)
+                .on_release(Message::IterativeSettingReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let relaxation_input = row![
+            text(format!("Relaxation: {:.2} ", self.processing.iterative.relaxation)),
+            slider(0.05..=2., self.processing.iterative.relaxation, Message::RelaxationChanged)
+                .step(0.05)
+                .on_release(Message::IterativeSettingReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let subsets_input = row![
+            text(format!("Subsets: {} ", self.processing.iterative.subsets)),
+            slider(1..=32, self.processing.iterative.subsets, Message::SubsetsChanged)
+                .on_release(Message::IterativeSettingReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let tv_weight_input = row![
+            text(format!("TV weight: {:.2} ", self.processing.iterative.tv_weight)),
+            slider(0.0..=2., self.processing.iterative.tv_weight, Message::TvWeightChanged)
+                .step(0.05)
+                .on_release(Message::IterativeSettingReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let metal_threshold_input = row![
+            text(format!("Metal threshold: {:.2} ", self.processing.metal.threshold)),
+            slider(0.05..=1., self.processing.metal.threshold, Message::MetalThresholdChanged)
+                .step(0.05)
+                .on_release(Message::MetalThresholdReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let non_negative_input = checkbox("Non-negative", self.processing.iterative.non_negative)
+            .on_toggle(Message::NonNegativeToggled);
+
+        let detector_offset = self.scan.as_ref().map_or(0., |scan| scan.detector_offset);
+        let detector_offset_input = row![
+            text(format!("Detector offset: {detector_offset:.2} mm ")),
+            slider(-10.0..=10., detector_offset, Message::DetectorOffsetChanged)
+                .step(0.01)
+                .on_release(Message::DetectorOffsetReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let mut detector_offset_estimate = row![
+            button("Estimate").on_press_maybe(self.scan.as_ref().map(|_| Message::EstimateDetectorOffsetPressed)),
+        ]
+        .spacing(5)
+        .width(Fill)
+        .align_y(Center);
+
+        if let Some(estimate) = self.detector_offset_estimate {
+            detector_offset_estimate = detector_offset_estimate
+                .push(text(format!("{estimate:.2} mm")))
+                .push(button("Apply").on_press(Message::ApplyDetectorOffsetPressed));
+        }
+
+        let rotation_direction_warning = self.detected_direction.map(|direction| row![
+            text(format!("Rotates {direction:?}? (fewer negative streaks) ")).width(Fill),
+            button("Fix descriptor").on_press(Message::FixRotationDirectionPressed),
+        ]
+        .width(Fill)
+        .align_y(Center));
+
+        let mut settings = column![
+            button(
+                container("Screenshot")
+                    .width(Fill)
+                    .align_x(Center)
+                )
+                .on_press(Message::ScreenshotPressed).width(Fill),
+            threshold_input,
+            resolution_input,
+            mode_input,
+        ]
+        .spacing(5);
+
+        // only show the settings that apply to the selected mode
+        settings = match self.processing.mode {
+            ReconstructionMode::Fbp | ReconstructionMode::Fdk => settings
+                .push(filter_input)
+                .push(cutoff_input),
+            ReconstructionMode::Sirt | ReconstructionMode::Sart => settings
+                .push(iterations_input)
+                .push(relaxation_input)
+                .push(non_negative_input),
+            ReconstructionMode::OsEm => settings
+                .push(iterations_input)
+                .push(subsets_input),
+            ReconstructionMode::Tv => settings
+                .push(iterations_input)
+                .push(relaxation_input)
+                .push(tv_weight_input),
+            ReconstructionMode::Mar => settings
+                .push(filter_input)
+                .push(cutoff_input)
+                .push(metal_threshold_input),
+        };
+
+        let normalize_intensity_input = checkbox("Normalize to air", self.processing.corrections.normalize_intensity)
+            .on_toggle(Message::NormalizeIntensityToggled);
+
+        let beam_hardening_input = row![
+            text("Beam hardening: "),
+            pick_list(
+                BeamHardening::ALL,
+                Some(self.processing.corrections.beam_hardening),
+                Message::BeamHardeningSelected
+            )
+            .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let beam_hardening_strength_input = row![
+            text(format!("Strength: {:.2} ", self.processing.corrections.beam_hardening_strength)),
+            slider(0.0..=0.5, self.processing.corrections.beam_hardening_strength, Message::BeamHardeningStrengthChanged)
+                .step(0.01)
+                .on_release(Message::BeamHardeningStrengthReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        let remove_rings_input = checkbox("Remove rings", self.processing.corrections.remove_rings)
+            .on_toggle(Message::RemoveRingsToggled);
+
+        let ring_removal_strength_input = row![
+            text(format!("Strength: {} ", self.processing.corrections.ring_removal_strength)),
+            slider(1..=30, self.processing.corrections.ring_removal_strength, Message::RingRemovalStrengthChanged)
+                .on_release(Message::RingRemovalStrengthReleased)
+                .width(Fill)
+        ]
+        .width(Fill)
+        .align_y(Center);
+
+        // the chain of denoising filters, with the size of each filter that is used
+        for (i, step) in self.processing.denoise.steps.iter().enumerate() {
+            settings = settings.push(denoise_step_input(i, step));
+        }
+
+        settings = settings
+            .push(normalize_intensity_input)
+            .push(beam_hardening_input);
+
+        if self.processing.corrections.beam_hardening == BeamHardening::Manual {
+            settings = settings.push(beam_hardening_strength_input);
+        }
+
+        settings = settings.push(remove_rings_input);
+
+        if self.processing.corrections.remove_rings {
+            settings = settings.push(ring_removal_strength_input);
+        }
+
+        settings = settings
+            .push(detector_offset_input)
+            .push(detector_offset_estimate)
+            .push_maybe(rotation_direction_warning);
+
+        let sidebar = container(settings)
         .style(container::dark)
         .width(FillPortion(20))
         .height(Fill)
@@ -155,12 +740,15 @@ impl Program for CTRS {
             sidebar,
         ].width(Fill).height(Fill);
 
-        column![
+        let main_view = column![
             top_bar,
             work_area
-        ].height(Fill).width(Fill)
-        
-        .into()
+        ].height(Fill).width(Fill);
+
+        match &self.load_errors {
+            Some(errors) => stack![main_view, load_errors_dialog(errors)].into(),
+            None => main_view.into(),
+        }
     }
 }
 
@@ -168,22 +756,188 @@ impl CTRS {
     pub fn subscription(&self) -> Subscription<Message> {
         window::frames().map(|_| Message::Tick )
     }
-}
 
-async fn load_scan() -> ScanLoadResult {
-    let handle = AsyncFileDialog::new()
-        .add_filter("Scan description file", &["json"])
-        .set_title("Pick scan")
-        .pick_file()
-        .await;
+    // changing the geometry of the scan requires the projections to be processed again
+    fn set_detector_offset(&mut self, detector_offset: f32) {
+        if let Some(scan) = &mut self.scan {
+            Arc::make_mut(scan).detector_offset = detector_offset;
+            self.geometry_generation += 1;
+        }
+    }
+
+    // (re)process the projections of the current scan with the current settings
+    fn process_projections(&mut self) -> Task<Message> {
+        let Some(scan) = self.scan.clone() else {
+            return Task::none();
+        };
+
+        // stop any iterative reconstruction with the old settings
+        self.reconstruction_task = None;
+
+        let settings = self.processing;
+        let generation = self.geometry_generation;
+        if settings.mode.on_cpu() {
+            self.status_message = format!("Reconstructing {}...", scan.name);
+
+            let (task, handle) = Task::run(
+                reconstruct_iteratively(scan, settings, self.volume_resolution),
+                move |(iteration, volume)| Message::VolumeReconstructed(generation, settings, iteration, volume)
+            ).abortable();
+            self.reconstruction_task = Some(handle.abort_on_drop());
+
+            return task;
+        }
+
+        self.status_message = format!("Processing projections of {}...", scan.name);
+
+        let resolution = self.volume_resolution;
+        Task::perform(
+            task::spawn_blocking(move || {
+                let projection_data = preprocess::preprocess(&scan, &settings);
+                if scene::projections_fit_on_gpu(&projection_data) {
+                    return Message::ProjectionsProcessed(generation, settings, projection_data.into());
+                }
+
+                // too many or too large projections for a texture, so they are back-projected here instead
+                log::warn!("The projections of {} don't fit in a texture, back-projecting them on the CPU", scan.name);
+                let volume = reconstruction::backprojection::backproject(
+                    &projection_data,
+                    &scene::projection_geometry(&scan),
+                    (resolution, resolution, resolution),
+                    scene::VOLUME_SIZE,
+                    settings.mode.is_cone_beam_weighted(),
+                );
+
+                Message::VolumeReconstructed(generation, settings, 1, Arc::new(volume))
+            }),
+            |message| message.unwrap()
+        )
+    }
+}
 
-    log::info!("Loading scan: {:?}", handle.as_ref());
+// The filter of a step in the denoising chain, followed by its size if it has a filter
+fn denoise_step_input(i: usize, step: &DenoiseStep) -> Element<'static, Message, Theme, iced_wgpu::Renderer> {
+    let filter_input = row![
+        text(format!("Denoise {}: ", i + 1)),
+        pick_list(
+            DenoiseFilter::ALL,
+            Some(step.filter),
+            move |filter| Message::DenoiseFilterSelected(i, filter)
+        )
+        .width(Fill)
+    ]
+    .width(Fill)
+    .align_y(Center);
 
-    match handle {
-        Some(path) => CtScan::from_file(path.path())
-            .await
-            .map_err(|err| ScanLoadError::FileLoadError(Arc::new(err)))
-            .map(Arc::new),
-        None => Err(ScanLoadError::NonePicked),
+    if step.filter == DenoiseFilter::None {
+        return filter_input.into();
     }
+
+    // window radii move in whole pixels, standard deviations in half pixels
+    let (range, size_step, precision) = if step.filter.has_integer_size() { (1.0..=5., 1., 0) } else { (0.5..=5., 0.5, 1) };
+    let size_input = row![
+        text(format!("Size: {:.*} px ", precision, step.size)),
+        slider(range, step.size, move |size| Message::DenoiseSizeChanged(i, size))
+            .step(size_step)
+            .on_release(Message::DenoiseSizeReleased)
+            .width(Fill)
+    ]
+    .width(Fill)
+    .align_y(Center);
+
+    column![filter_input, size_input].spacing(5).into()
+}
+
+// Modal dialog listing the problems with a scan that couldn't be loaded, on top of a dimmed background
+fn load_errors_dialog(errors: &[LoadError]) -> Element<'static, Message, Theme, iced_wgpu::Renderer> {
+    let problems = errors.iter().fold(column![].spacing(5), |problems, error| problems.push(text(error.to_string())));
+
+    let dialog = container(
+        column![
+            text("The scan couldn't be loaded").size(20),
+            scrollable(problems),
+            container(button("OK").on_press(Message::LoadErrorsDismissed)).width(Fill).align_x(Horizontal::Right),
+        ]
+        .spacing(10)
+    )
+    .style(container::rounded_box)
+    .max_width(600)
+    .max_height(400)
+    .padding(10);
+
+    // clicking next to the dialog dismisses it as well
+    opaque(
+        mouse_area(
+            center(opaque(dialog)).style(|_theme| container::Style {
+                background: Some(Color { a: 0.8, ..Color::BLACK }.into()),
+                ..container::Style::default()
+            })
+        )
+        .on_press(Message::LoadErrorsDismissed)
+    )
+}
+
+// Reconstruct a scan on the CPU, yielding the volume after every iteration (or once when done).
+// The reconstruction stops after the current iteration when the stream is dropped.
+fn reconstruct_iteratively(
+    scan: Arc<CtScan>,
+    settings: ProcessingSettings,
+    resolution: u32
+) -> impl Stream<Item = (u32, Arc<Volume>)> {
+    iced::stream::channel(1, move |mut output| async move {
+        let (sender, mut receiver) = mpsc::unbounded();
+
+        let worker = task::spawn_blocking(move || {
+            reconstruction::reconstruct(&scan, &settings, resolution, |iteration, volume| {
+                sender.unbounded_send((iteration, Arc::new(volume.clone()))).is_ok()
+            })
+        });
+
+        while let Some(update) = receiver.next().await {
+            if output.send(update).await.is_err() {
+                break;
+            }
+        }
+
+        // dropping the receiver makes the worker stop after its current iteration
+        drop(receiver);
+        let _ = worker.await;
+    })
+}
+
+// Pick a scan descriptor and load it, yielding the progress of loading the images and then the scan.
+// Loading stops when the stream is dropped.
+fn load_scan() -> impl Stream<Item = Message> {
+    iced::stream::channel(1, move |mut output| async move {
+        let handle = AsyncFileDialog::new()
+            .add_filter("Scan description file", &["json"])
+            .set_title("Pick scan")
+            .pick_file()
+            .await;
+
+        log::info!("Loading scan: {:?}", handle.as_ref());
+
+        let Some(path) = handle else {
+            let _ = output.send(Message::ScanLoaded(Err(ScanLoadError::NonePicked))).await;
+            return;
+        };
+
+        let (sender, mut receiver) = mpsc::unbounded();
+        let loading = CtScan::from_file(path.path(), move |loaded, total| {
+            let _ = sender.unbounded_send(Message::ScanLoadProgress(loaded, total));
+        });
+
+        // the progress ends when the scan is loaded, which drops the sender
+        let (result, ()) = future::join(loading, async {
+            while let Some(progress) = receiver.next().await {
+                let _ = output.send(progress).await;
+            }
+        })
+        .await;
+
+        let result = result
+            .map_err(|errors| ScanLoadError::FileLoadError(errors.into()))
+            .map(Arc::new);
+        let _ = output.send(Message::ScanLoaded(result)).await;
+    })
 }
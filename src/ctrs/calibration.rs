@@ -0,0 +1,260 @@
+pub mod beads;
+
+use rayon::prelude::*;
+
+use super::{
+    preprocess::{self, ProcessingSettings},
+    reconstruction::{backprojection, ReconstructionMode, Volume},
+    scan::{CtScan, RotationDirection, ScanImage},
+    scene::{projection_geometry, VOLUME_SIZE},
+};
+
+// number of detector rows around the center that are averaged when comparing projections
+const PROFILE_ROWS: u32 = 16;
+// max number of opposing projection pairs used to estimate the detector offset
+const MAX_PAIRS: usize = 16;
+// voxels along the horizontal axes and number of slices of the previews that the rotation direction is detected from
+const PREVIEW_RESOLUTION: u32 = 64;
+const PREVIEW_SLICES: u32 = 8;
+// the preview with the wrong direction needs at least this many times the negativity of the other one,
+// so noise alone doesn't make the directions look different
+const MIN_RATIO: f32 = 1.3;
+
+// Attenuation profile along the central rows of a projection, so the background is close to zero
+fn central_profile(image: &ScanImage) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let rows = (height / 2).saturating_sub(PROFILE_ROWS / 2)..(height / 2 + PROFILE_ROWS / 2).min(height);
+    let n_rows = rows.len() as f32;
+
+    (0..width)
+        .map(|x| {
+            rows.clone()
+                .map(|y| -image.get_pixel(x, y).0[0].max(f32::MIN_POSITIVE).ln())
+                .sum::<f32>() / n_rows
+        })
+        .collect()
+}
+
+// The shift (in pixels) of `b` that best matches `a`, found by minimizing the mean squared
+// difference where they overlap and refined to sub-pixel precision with a parabola.
+fn best_shift(a: &[f32], b: &[f32], max_shift: i32) -> Option<f32> {
+    let len = a.len() as i32;
+    let error = |shift: i32| {
+        let (start, end) = (0.max(-shift), len.min(len - shift));
+        let sum: f32 = (start..end)
+            .map(|x| (a[x as usize] - b[(x + shift) as usize]).powi(2))
+            .sum();
+
+        sum / (end - start) as f32
+    };
+
+    let errors: Vec<f32> = (-max_shift..=max_shift).map(error).collect();
+    let best = errors.iter().enumerate().min_by(|a, b| a.1.total_cmp(b.1))?.0;
+
+    let offset = if best > 0 && best < errors.len() - 1 {
+        let (left, center, right) = (errors[best - 1], errors[best], errors[best + 1]);
+        let curvature = left - 2.*center + right;
+        if curvature > 0. { 0.5 * (left - right) / curvature } else { 0. }
+    } else {
+        0.
+    };
+
+    Some(best as f32 - max_shift as f32 + offset)
+}
+
+// Estimate the horizontal detector offset of a scan (in mm) by matching opposing projections.
+// A projection mirrored horizontally is roughly the same as the projection taken 180° away,
+// except that it is shifted by twice the distance between the detector center and the projected
+// rotation axis. Returns None if the scan doesn't have any projections 180° apart.
+pub fn estimate_detector_offset(scan: &CtScan) -> Option<f32> {
+    let angles = scan.projection_angles();
+    let images = &scan.projection_images;
+    if images.len() < 2 {
+        return None;
+    }
+
+    // opposing projections have to be closer than half a step to 180° apart
+    let step = scan.angle_step();
+    let mut pairs: Vec<(usize, usize)> = (0..images.len())
+        .filter_map(|i| {
+            let (j, distance) = angles.iter().enumerate()
+                .map(|(j, angle)| (j, ((angle - angles[i]).rem_euclid(360.) - 180.).abs()))
+                .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+            (distance <= step / 2.).then_some((i, j))
+        })
+        .collect();
+
+    if pairs.is_empty() {
+        return None;
+    }
+
+    // spread the used pairs over the whole scan
+    let stride = pairs.len().div_ceil(MAX_PAIRS);
+    pairs = pairs.into_iter().step_by(stride).collect();
+
+    let width = images[0].width();
+    let mut shifts: Vec<f32> = pairs.par_iter()
+        .filter_map(|&(i, j)| {
+            let profile = central_profile(&images[i]);
+            let mut opposing = central_profile(&images[j]);
+            opposing.reverse();
+
+            best_shift(&profile, &opposing, width as i32 / 4)
+        })
+        .collect();
+
+    shifts.sort_by(f32::total_cmp);
+    let median_shift = shifts[shifts.len() / 2];
+
+    // convert from pixels to mm using the geometry of the scan, without any offset
+    let projection = &projection_geometry(&CtScan { detector_offset: 0., ..scan.clone() })[0];
+    let pitch = projection.texture_to_projection([1. / width as f32, 0.5])[0]
+        - projection.texture_to_projection([0., 0.5])[0];
+
+    Some(median_shift / 2. * pitch.abs())
+}
+
+// Energy of the negative voxels of a volume relative to the energy of all voxels. Reconstructing
+// with the wrong rotation direction makes the rays through each feature of the object miss each
+// other, which leaves more negative streaks.
+fn negativity(volume: &Volume) -> f32 {
+    let negative: f32 = volume.data.iter().filter(|voxel| **voxel < 0.).map(|voxel| voxel * voxel).sum();
+    let total: f32 = volume.data.iter().map(|voxel| voxel * voxel).sum();
+
+    if total > 0. { negative / total } else { 0. }
+}
+
+// Detect the direction the scan rotates in by reconstructing a few low resolution slices with either
+// direction and picking the one with clearly fewer negative streaks. The directions only differ by the
+// perspective of the cone beam, so this works best for objects that fill the field of view. Returns None
+// if the scan doesn't have enough projections or the difference between the directions is too small to tell.
+// Short scans are skipped, as the Parker weights leave streaks of their own in either direction.
+pub fn detect_rotation_direction(scan: &CtScan) -> Option<RotationDirection> {
+    if scan.projection_images.len() < 2 || scan.sweep() < 360. - 1e-3 {
+        return None;
+    }
+
+    let settings = ProcessingSettings {
+        mode: ReconstructionMode::Fdk,
+        ..Default::default()
+    };
+    // the previews are low resolution, so the projections are binned before anything else
+    let line_integrals = preprocess::line_integrals(scan, &settings);
+    let factor = preprocess::bin_factor(line_integrals[0].dimensions(), 2*PREVIEW_RESOLUTION);
+    let line_integrals = preprocess::bin(&line_integrals, factor);
+    let dimensions = (PREVIEW_RESOLUTION, PREVIEW_RESOLUTION, PREVIEW_SLICES);
+
+    let directions = [RotationDirection::CW, RotationDirection::CCW];
+    let negativities = directions.map(|direction| {
+        let scan = CtScan { direction, ..scan.clone() };
+
+        let mut filtered = line_integrals.clone();
+        preprocess::weight_and_filter(&mut filtered, &scan, &settings);
+
+        negativity(&backprojection::backproject(&filtered, &projection_geometry(&scan), dimensions, VOLUME_SIZE, true))
+    });
+
+    log::info!("Negativity of the previews: {negativities:?} (CW, CCW)");
+
+    let (best, worst) = if negativities[0] <= negativities[1] { (0, 1) } else { (1, 0) };
+    (negativities[worst] > MIN_RATIO * negativities[best]).then_some(directions[best])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use crate::ctrs::scan::ProjectionFile;
+
+    use super::*;
+
+    // radius and position of the spheres of a phantom
+    type Phantom = [(f32, [f32; 3])];
+
+    // A scan of spheres with unit attenuation, where the detector is shifted by the given offset
+    // and the transmission has some noise of the given standard deviation
+    fn scan_spheres(direction: RotationDirection, swept_angle: f32, detector_offset: f32, spheres: &Phantom, noise: f32) -> CtScan {
+        let mut scan: CtScan = serde_json::from_value(serde_json::json!({
+            "name": "Spheres",
+            "direction": direction,
+            "sod": 100.,
+            "sdd": 150.,
+            "swept_angle": swept_angle,
+            "pixel_size": 0.4,
+            "detector_offset": detector_offset,
+            "projections": [],
+        }))
+        .unwrap();
+
+        let (width, height) = (256, 64);
+        scan.projection_files = (0..120).map(|i| ProjectionFile::Path(format!("{i}.tif").into())).collect();
+        scan.projection_images = vec![ScanImage::new(width, height); scan.projection_files.len()].into();
+
+        scan.projection_images = projection_geometry(&scan).iter().enumerate()
+            .map(|(i, projection)| {
+                let source = projection.source_position();
+
+                ScanImage::from_fn(width, height, |x, y| {
+                    let point_texture = [(x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32];
+                    let point_world = projection.detector_to_world(projection.texture_to_projection(point_texture));
+                    let ray: [f32; 3] = std::array::from_fn(|i| point_world[i] - source[i]);
+                    let length = ray.iter().map(|d| d*d).sum::<f32>().sqrt();
+
+                    // the length of the chord of each sphere that the ray passes through
+                    let line_integral: f32 = spheres.iter()
+                        .map(|(radius, center)| {
+                            let to_center: [f32; 3] = std::array::from_fn(|i| center[i] - source[i]);
+                            let along = to_center.iter().zip(&ray).map(|(a, b)| a * b).sum::<f32>() / length;
+                            let distance2 = to_center.iter().map(|d| d*d).sum::<f32>() - along*along;
+
+                            2. * (radius*radius - distance2).max(0.).sqrt()
+                        })
+                        .sum();
+
+                    // uniform noise from a hash of the pixel, with the given standard deviation
+                    let hash = ((x * 7919 + y * 104729 + i as u32 * 1299709) % 10007) as f32 / 10007.;
+                    Luma([(-0.1 * line_integral).exp() + noise * 12f32.sqrt() * (hash - 0.5)])
+                })
+            })
+            .collect::<Vec<_>>()
+            .into();
+
+        scan
+    }
+
+    #[test]
+    fn estimates_the_detector_offset_of_forward_and_reverse_scans() {
+        let spheres = [(6., [0., 0., 0.]), (2., [4., 3., 1.])];
+
+        for swept_angle in [360., -360.] {
+            let scan = scan_spheres(RotationDirection::CW, swept_angle, 0.6, &spheres, 0.);
+            let estimate = estimate_detector_offset(&scan).unwrap();
+
+            assert!((estimate - 0.6).abs() < 0.05, "{estimate} for a sweep of {swept_angle}°");
+        }
+    }
+
+    #[test]
+    fn detects_the_rotation_direction_of_an_asymmetric_object() {
+        // a large object with smaller ones off to the side, which smear when reconstructed the wrong way round
+        let spheres = [(20., [0., 0., 0.]), (4., [15., 8., 2.]), (3., [-10., 14., -3.]), (5., [5., -16., 0.])];
+
+        for direction in [RotationDirection::CW, RotationDirection::CCW] {
+            let scan = scan_spheres(direction, 360., 0., &spheres, 0.005);
+
+            assert_eq!(detect_rotation_direction(&scan), Some(direction));
+        }
+    }
+
+    #[test]
+    fn noise_doesnt_tell_the_directions_of_a_symmetric_object_apart() {
+        let spheres = [(20., [0., 0., 0.])];
+
+        for direction in [RotationDirection::CW, RotationDirection::CCW] {
+            let scan = scan_spheres(direction, 360., 0., &spheres, 0.02);
+
+            assert_eq!(detect_rotation_direction(&scan), None);
+        }
+    }
+}
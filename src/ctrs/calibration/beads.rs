@@ -0,0 +1,516 @@
+use rayon::prelude::*;
+
+use crate::ctrs::{
+    preprocess::{self, ProcessingSettings},
+    scan::{CtScan, ScanImage},
+    scene::{pipeline::uniforms::Projection, projection_geometry},
+};
+
+// the background is estimated with a blur whose radius is the detector size divided by this
+const BACKGROUND_RADIUS: u32 = 16;
+// pixels that stand out from the background by more than this fraction of the beads are part of a bead
+const DETECTION_THRESHOLD: f32 = 0.5;
+// smaller groups of pixels are noise rather than beads
+const MIN_BEAD_PIXELS: usize = 4;
+// beads move less than the detector width divided by this between neighbouring projections
+const MAX_JUMP: u32 = 25;
+// beads that are seen in less than this fraction of the projections aren't used
+const MIN_OBSERVED: f32 = 0.5;
+const MAX_ITERATIONS: usize = 100;
+// step (in mm or degrees) of the finite differences the derivatives are approximated with
+const DIFFERENCE_STEP: f64 = 1e-2;
+
+// Weight (in pixels per degree) of keeping the pitch and yaw of the detector close to the descriptor.
+// They hardly change the projections of the beads, so they would drift off with the noise otherwise.
+const PRIOR_WEIGHT: f64 = 0.1;
+
+// the geometry parameters that are calibrated, in the order they are stored in the parameter vector
+const GEOMETRY_PARAMETERS: usize = 6;
+
+// Positions (in pixels) of the beads that were detected in a projection
+type Detections = Vec<[f32; 2]>;
+
+// A bead followed through the projections, with its position in each projection it was seen in
+type Track = Vec<Option<[f32; 2]>>;
+
+// The result of calibrating the geometry of a scan
+#[derive(Debug)]
+pub struct GeometryCalibration {
+    // the scan with the calibrated geometry
+    pub scan: CtScan,
+    pub n_beads: usize,
+    // root mean square distance (in pixels) between the detected and the projected beads,
+    // with the geometry from the descriptor and the calibrated geometry
+    pub initial_error: f32,
+    pub error: f32,
+}
+
+// Box blur of the values with the given radius, where the image is extended by clamping it to its edges
+fn box_blur(values: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+    let blur_lines = |values: &[f32], len: usize, n_lines: usize, index: &dyn Fn(usize, usize) -> usize| {
+        let mut blurred = vec![0.; values.len()];
+        for line in 0..n_lines {
+            let at = |i: isize| values[index(line, i.clamp(0, len as isize - 1) as usize)];
+
+            let mut sum: f32 = (-(radius as isize)..=radius as isize).map(at).sum();
+            for i in 0..len {
+                blurred[index(line, i)] = sum / (2*radius + 1) as f32;
+                sum += at(i as isize + radius as isize + 1) - at(i as isize - radius as isize);
+            }
+        }
+        blurred
+    };
+
+    let horizontal = blur_lines(values, width, height, &|y, x| y*width + x);
+    blur_lines(&horizontal, height, width, &|x, y| y*width + x)
+}
+
+// How much each pixel of the line integrals stands out from the background around it
+fn bead_contrast(image: &ScanImage) -> Vec<f32> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let radius = width.max(height) / BACKGROUND_RADIUS as usize;
+    let background = box_blur(image.as_raw(), width, height, radius);
+
+    image.iter().zip(background).map(|(sample, background)| sample - background).collect()
+}
+
+// Centroids of the groups of connected pixels above the threshold, weighted by their contrast
+fn detect_beads(contrast: &[f32], width: usize, threshold: f32) -> Detections {
+    let height = contrast.len() / width;
+    let mut visited = vec![false; contrast.len()];
+    let mut beads = Vec::new();
+
+    for start in 0..contrast.len() {
+        if visited[start] || contrast[start] <= threshold {
+            continue;
+        }
+
+        // flood fill the bead, summing the weighted positions of its pixels
+        let (mut n_pixels, mut total, mut sum) = (0, 0., [0., 0.]);
+        let mut stack = vec![start];
+        visited[start] = true;
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % width, i / width);
+            n_pixels += 1;
+            total += contrast[i];
+            sum[0] += contrast[i] * x as f32;
+            sum[1] += contrast[i] * y as f32;
+
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                let (nx, ny) = (x as isize + dx, y as isize + dy);
+                if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                    continue;
+                }
+
+                let neighbour = ny as usize * width + nx as usize;
+                if !visited[neighbour] && contrast[neighbour] > threshold {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        if n_pixels >= MIN_BEAD_PIXELS {
+            beads.push([sum[0] / total, sum[1] / total]);
+        }
+    }
+
+    beads
+}
+
+// Follow the beads through the projections in the given order, starting from their positions in
+// the projection before. Each bead is matched with the detection closest to where it would be if it
+// kept moving like between its last two positions, unless that is also the closest detection of
+// another bead, which happens when the two overlap.
+fn follow(tracks: &mut [Track], detections: &[Detections], order: impl Iterator<Item = usize>, max_jump: f32) {
+    let mut last: Vec<[f32; 2]> = tracks.iter().map(|track| track.iter().flatten().next().copied().unwrap()).collect();
+    let mut velocities = vec![[0f32; 2]; tracks.len()];
+    let mut gaps = vec![0; tracks.len()];
+
+    for i in order {
+        let closest: Vec<Option<usize>> = (0..tracks.len())
+            .map(|k| {
+                let steps = (gaps[k] + 1) as f32;
+                let predicted = [last[k][0] + velocities[k][0]*steps, last[k][1] + velocities[k][1]*steps];
+
+                detections[i].iter().enumerate()
+                    .map(|(j, detection)| (j, (detection[0] - predicted[0]).hypot(detection[1] - predicted[1])))
+                    .filter(|(_, distance)| *distance <= max_jump * steps)
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(j, _)| j)
+            })
+            .collect();
+
+        for (k, track) in tracks.iter_mut().enumerate() {
+            let unique = closest[k].filter(|j| closest.iter().filter(|other| **other == Some(*j)).count() == 1);
+
+            match unique {
+                Some(j) => {
+                    let detection = detections[i][j];
+                    let steps = (gaps[k] + 1) as f32;
+                    velocities[k] = [(detection[0] - last[k][0]) / steps, (detection[1] - last[k][1]) / steps];
+
+                    track[i] = Some(detection);
+                    last[k] = detection;
+                    gaps[k] = 0;
+                },
+                None => gaps[k] += 1,
+            }
+        }
+    }
+}
+
+// Detect the beads in every projection and follow each of them through the scan
+fn track_beads(scan: &CtScan) -> Result<Vec<Track>, String> {
+    let line_integrals = preprocess::line_integrals(scan, &ProcessingSettings::default());
+    let Some(first) = line_integrals.first() else {
+        return Err("The scan doesn't have any projections".into());
+    };
+    let width = first.width() as usize;
+
+    let contrasts: Vec<Vec<f32>> = line_integrals.par_iter().map(bead_contrast).collect();
+
+    // the threshold is relative to how much the beads typically stand out
+    let mut maxima: Vec<f32> = contrasts.iter().map(|contrast| contrast.iter().copied().fold(0., f32::max)).collect();
+    let middle = maxima.len() / 2;
+    let threshold = DETECTION_THRESHOLD * *maxima.select_nth_unstable_by(middle, f32::total_cmp).1;
+
+    let detections: Vec<Detections> = contrasts.par_iter()
+        .map(|contrast| detect_beads(contrast, width, threshold))
+        .collect();
+
+    // the number of beads is the number that is detected most often, when none of them overlap
+    let n_beads = (1..=detections.iter().map(Vec::len).max().unwrap_or(0))
+        .max_by_key(|n| detections.iter().filter(|beads| beads.len() == *n).count())
+        .ok_or("No beads were found in the projections")?;
+    let start = detections.iter().position(|beads| beads.len() == n_beads).unwrap();
+
+    let mut tracks: Vec<Track> = detections[start].iter()
+        .map(|bead| {
+            let mut track = vec![None; detections.len()];
+            track[start] = Some(*bead);
+            track
+        })
+        .collect();
+
+    let max_jump = (width / MAX_JUMP as usize) as f32;
+    follow(&mut tracks, &detections, start + 1..detections.len(), max_jump);
+    follow(&mut tracks, &detections, (0..start).rev(), max_jump);
+
+    let min_observed = (MIN_OBSERVED * detections.len() as f32) as usize;
+    tracks.retain(|track| track.iter().flatten().count() >= min_observed);
+
+    if tracks.len() < 2 {
+        return Err(format!("Only {} beads could be followed through the scan, at least 2 are needed", tracks.len()));
+    }
+
+    Ok(tracks)
+}
+
+// The ray from the source through a pixel of a projection, as the position of the source and a unit direction
+fn pixel_ray(projection: &Projection, pixel: [f32; 2], dimensions: (u32, u32)) -> ([f64; 3], [f64; 3]) {
+    let point_texture = [(pixel[0] + 0.5) / dimensions.0 as f32, (pixel[1] + 0.5) / dimensions.1 as f32];
+    let point_world = projection.detector_to_world(projection.texture_to_projection(point_texture));
+    let source = projection.source_position();
+
+    let direction: [f64; 3] = std::array::from_fn(|i| (point_world[i] - source[i]) as f64);
+    let length = direction.iter().map(|d| d*d).sum::<f64>().sqrt();
+
+    (source.map(|coord| coord as f64), direction.map(|d| d / length))
+}
+
+// The point closest to all rays that a bead was seen along, in the least squares sense
+fn triangulate(track: &Track, projections: &[Projection], dimensions: (u32, u32)) -> Option<[f64; 3]> {
+    let mut a = vec![vec![0.; 3]; 3];
+    let mut b = vec![0.; 3];
+
+    for (projection, pixel) in projections.iter().zip(track) {
+        let Some(pixel) = pixel else {
+            continue;
+        };
+
+        // the distance to the ray is the length of the component orthogonal to its direction
+        let (source, direction) = pixel_ray(projection, *pixel, dimensions);
+        for row in 0..3 {
+            for col in 0..3 {
+                let orthogonal = if row == col { 1. } else { 0. } - direction[row] * direction[col];
+                a[row][col] += orthogonal;
+                b[row] += orthogonal * source[col];
+            }
+        }
+    }
+
+    solve(a, b).map(|point| [point[0], point[1], point[2]])
+}
+
+// Solve the linear system a·x = b with Gaussian elimination, or None if it is singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in col+1..n {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col].clone();
+            for (element, pivot_element) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                *element -= factor * pivot_element;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row+1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+
+    Some(x)
+}
+
+// The scan with the geometry parameters from the start of the parameter vector
+fn with_geometry(scan: &CtScan, parameters: &[f64]) -> CtScan {
+    CtScan {
+        sdd: parameters[0] as f32,
+        detector_offset: parameters[1] as f32,
+        detector_vertical_offset: parameters[2] as f32,
+        detector_roll: parameters[3] as f32,
+        detector_pitch: parameters[4] as f32,
+        detector_yaw: parameters[5] as f32,
+        ..scan.clone()
+    }
+}
+
+// Differences (in pixels) between the projected and the detected positions of the beads, for the
+// geometry and bead positions in the parameter vector
+fn residuals(scan: &CtScan, parameters: &[f64], tracks: &[Track], dimensions: (u32, u32)) -> Vec<f64> {
+    let projections = projection_geometry(&with_geometry(scan, parameters));
+    let beads = parameters[GEOMETRY_PARAMETERS..].chunks_exact(3);
+
+    beads.zip(tracks)
+        .flat_map(|(bead, track)| {
+            let bead_world = [bead[0] as f32, bead[1] as f32, bead[2] as f32];
+
+            projections.iter().zip(track).filter_map(move |(projection, pixel)| {
+                let pixel = (*pixel)?;
+                let point_proj = projection.project_point(bead_world);
+                let point_texture = projection.projection_to_texture([point_proj[0], point_proj[1]]);
+
+                Some([
+                    (point_texture[0] * dimensions.0 as f32 - 0.5 - pixel[0]) as f64,
+                    (point_texture[1] * dimensions.1 as f32 - 0.5 - pixel[1]) as f64,
+                ])
+            })
+        })
+        .flatten()
+        .collect()
+}
+
+fn rms(residuals: &[f64]) -> f32 {
+    (residuals.iter().map(|r| r*r).sum::<f64>() / residuals.len() as f64).sqrt() as f32
+}
+
+// Minimize the squared residuals over the parameters with the Levenberg-Marquardt algorithm,
+// using central differences for the derivatives
+fn levenberg_marquardt(mut parameters: Vec<f64>, residuals: impl Fn(&[f64]) -> Vec<f64> + Sync) -> Vec<f64> {
+    let n = parameters.len();
+    let cost = |r: &[f64]| r.iter().map(|r| r*r).sum::<f64>();
+
+    let mut current = residuals(&parameters);
+    let mut lambda = 1e-3;
+
+    for _ in 0..MAX_ITERATIONS {
+        let jacobian: Vec<Vec<f64>> = (0..n).into_par_iter()
+            .map(|k| {
+                let mut forward = parameters.clone();
+                let mut backward = parameters.clone();
+                forward[k] += DIFFERENCE_STEP;
+                backward[k] -= DIFFERENCE_STEP;
+
+                residuals(&forward).iter().zip(residuals(&backward))
+                    .map(|(f, b)| (f - b) / (2. * DIFFERENCE_STEP))
+                    .collect()
+            })
+            .collect();
+
+        let jtj: Vec<Vec<f64>> = (0..n)
+            .map(|row| (0..n).map(|col| jacobian[row].iter().zip(&jacobian[col]).map(|(a, b)| a*b).sum()).collect())
+            .collect();
+        let jtr: Vec<f64> = jacobian.iter().map(|column| column.iter().zip(&current).map(|(a, b)| a*b).sum()).collect();
+
+        // increase the damping until a step reduces the cost
+        let improved = loop {
+            let mut damped = jtj.clone();
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += lambda * (jtj[i][i] + 1e-9);
+            }
+
+            let step = solve(damped, jtr.iter().map(|g| -g).collect());
+            let candidate: Option<Vec<f64>> = step.map(|step| parameters.iter().zip(step).map(|(p, s)| p + s).collect());
+            let candidate_residuals = candidate.as_ref().map(|candidate| residuals(candidate));
+
+            match (candidate, candidate_residuals) {
+                (Some(candidate), Some(candidate_residuals)) if cost(&candidate_residuals) < cost(&current) => {
+                    let relative = (cost(&current) - cost(&candidate_residuals)) / cost(&current);
+                    parameters = candidate;
+                    current = candidate_residuals;
+                    lambda = (lambda / 10.).max(1e-12);
+                    break relative > 1e-10;
+                },
+                _ if lambda > 1e10 => break false,
+                _ => lambda *= 10.,
+            }
+        };
+
+        if !improved {
+            break;
+        }
+    }
+
+    parameters
+}
+
+// Calibrate the geometry of a scan of a phantom with a few small high-contrast beads. The beads are
+// detected in every projection and followed through the scan, and the sdd, detector offsets and
+// tilts are fitted together with the positions of the beads, such that the projected beads match the
+// detected ones.
+//
+// Scaling the beads and the sod around the source gives the same projections, so the sod can only be
+// found if the distance (in mm) between the two beads that are the farthest apart is given. Otherwise
+// the sod from the descriptor is kept.
+pub fn calibrate_geometry(scan: &CtScan, bead_distance: Option<f32>) -> Result<GeometryCalibration, String> {
+    let tracks = track_beads(scan)?;
+    let dimensions = scan.projection_images[0].dimensions();
+    log::info!("Following {} beads through the scan", tracks.len());
+
+    let projections = projection_geometry(scan);
+    let mut parameters = vec![
+        scan.sdd as f64,
+        scan.detector_offset as f64,
+        scan.detector_vertical_offset as f64,
+        scan.detector_roll as f64,
+        scan.detector_pitch as f64,
+        scan.detector_yaw as f64,
+    ];
+    for track in &tracks {
+        let bead = triangulate(track, &projections, dimensions).ok_or("Couldn't triangulate the position of a bead")?;
+        parameters.extend(bead);
+    }
+
+    let (initial_pitch, initial_yaw) = (parameters[4], parameters[5]);
+    let residuals = |parameters: &[f64]| residuals(scan, parameters, &tracks, dimensions);
+    let initial_error = rms(&residuals(&parameters));
+
+    let parameters = levenberg_marquardt(parameters, |parameters| {
+        let prior = [PRIOR_WEIGHT * (parameters[4] - initial_pitch), PRIOR_WEIGHT * (parameters[5] - initial_yaw)];
+        residuals(parameters).into_iter().chain(prior).collect()
+    });
+    let error = rms(&residuals(&parameters));
+
+    let mut calibrated = with_geometry(scan, &parameters);
+
+    if let Some(bead_distance) = bead_distance {
+        let beads: Vec<&[f64]> = parameters[GEOMETRY_PARAMETERS..].chunks_exact(3).collect();
+        let farthest = beads.iter()
+            .flat_map(|a| beads.iter().map(move |b| (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f64>().sqrt()))
+            .fold(0., f64::max) as f32;
+
+        // scale everything but the detector around the source, which keeps the projections the same
+        let scale = bead_distance / farthest;
+        calibrated.sod *= scale;
+        calibrated.detector_vertical_offset -= calibrated.source_vertical_offset * (1. - scale);
+        calibrated.source_vertical_offset *= scale;
+    }
+
+    Ok(GeometryCalibration {
+        scan: calibrated,
+        n_beads: tracks.len(),
+        initial_error,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use crate::ctrs::scan::ProjectionFile;
+
+    use super::*;
+
+    const N_PROJECTIONS: usize = 90;
+    const WIDTH: u32 = 160;
+    const HEIGHT: u32 = 96;
+
+    // a 360° scan with the given geometry, without images
+    fn bead_scan(sdd: f32, detector_offset: f32, detector_roll: f32) -> CtScan {
+        let mut scan: CtScan = serde_json::from_value(serde_json::json!({
+            "name": "Beads",
+            "direction": "CW",
+            "sod": 100.,
+            "sdd": sdd,
+            "swept_angle": 360.,
+            "pixel_size": 0.2,
+            "detector_offset": detector_offset,
+            "detector_roll": detector_roll,
+            "projections": [],
+        }))
+        .unwrap();
+
+        scan.projection_files = (0..N_PROJECTIONS).map(|i| ProjectionFile::Path(format!("{i}.tif").into())).collect();
+        scan
+    }
+
+    // Projections of a few beads with the given geometry, where each bead is a Gaussian blob in the line integrals
+    fn project_beads(scan: &mut CtScan, beads: &[[f32; 3]]) {
+        scan.projection_images = vec![ScanImage::new(WIDTH, HEIGHT); N_PROJECTIONS].into();
+        let projections = projection_geometry(scan);
+
+        scan.projection_images = projections.iter()
+            .map(|projection| {
+                let centers: Vec<[f32; 2]> = beads.iter()
+                    .map(|bead| {
+                        let point_proj = projection.project_point(*bead);
+                        let point_texture = projection.projection_to_texture([point_proj[0], point_proj[1]]);
+                        [point_texture[0] * WIDTH as f32 - 0.5, point_texture[1] * HEIGHT as f32 - 0.5]
+                    })
+                    .collect();
+
+                ScanImage::from_fn(WIDTH, HEIGHT, |x, y| {
+                    let line_integral: f32 = centers.iter()
+                        .map(|center| (-((x as f32 - center[0]).powi(2) + (y as f32 - center[1]).powi(2)) / 8.).exp())
+                        .sum();
+                    Luma([(-line_integral).exp()])
+                })
+            })
+            .collect::<Vec<_>>()
+            .into();
+    }
+
+    #[test]
+    fn recovers_the_geometry_of_projected_beads() {
+        let beads = [[7., 0., 3.], [-5., 5., -2.], [2., -7., -4.], [0., 4., 5.]];
+
+        // the descriptor is off in the sdd, detector offset and roll
+        let mut actual = bead_scan(150., 0.6, 1.);
+        project_beads(&mut actual, &beads);
+        let scan = CtScan {
+            projection_images: actual.projection_images.clone(),
+            ..bead_scan(140., 0., 0.)
+        };
+
+        let calibration = calibrate_geometry(&scan, None).unwrap();
+        let calibrated = &calibration.scan;
+
+        assert_eq!(calibration.n_beads, beads.len());
+        assert!(calibration.error < 0.15, "{}", calibration.error);
+        assert!((calibrated.sdd - 150.).abs() < 1., "{}", calibrated.sdd);
+        assert!((calibrated.detector_offset - 0.6).abs() < 0.02, "{}", calibrated.detector_offset);
+        assert!((calibrated.detector_roll - 1.).abs() < 0.05, "{}", calibrated.detector_roll);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{f32::consts::PI, fmt::Display, sync::Arc};
+
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+use super::scan::ScanImage;
+
+// Window applied on top of the ramp (|f|) filter used for filtered back-projection.
+// See chapter 3 of Kak & Slaney, "Principles of Computerized Tomographic Imaging".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RampFilter {
+    None, // plain (unfiltered) back-projection
+    #[default]
+    RamLak,
+    SheppLogan,
+    Cosine,
+    Hamming,
+    Hann,
+}
+
+impl RampFilter {
+    pub const ALL: [RampFilter; 6] = [
+        RampFilter::None,
+        RampFilter::RamLak,
+        RampFilter::SheppLogan,
+        RampFilter::Cosine,
+        RampFilter::Hamming,
+        RampFilter::Hann,
+    ];
+
+    // value of the window at frequency f, where f and cutoff are given as
+    // fractions of the Nyquist frequency
+    fn window(&self, f: f32, cutoff: f32) -> f32 {
+        if f > cutoff {
+            return 0.;
+        }
+
+        let x = f/cutoff;
+        match self {
+            RampFilter::None | RampFilter::RamLak => 1.,
+            RampFilter::SheppLogan => {
+                if x == 0. {
+                    1.
+                } else {
+                    (PI*x/2.).sin() / (PI*x/2.)
+                }
+            },
+            RampFilter::Cosine => (PI*x/2.).cos(),
+            RampFilter::Hamming => 0.54 + 0.46*(PI*x).cos(),
+            RampFilter::Hann => 0.5 + 0.5*(PI*x).cos(),
+        }
+    }
+}
+
+impl Display for RampFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RampFilter::None => "None",
+            RampFilter::RamLak => "Ram-Lak",
+            RampFilter::SheppLogan => "Shepp-Logan",
+            RampFilter::Cosine => "Cosine",
+            RampFilter::Hamming => "Hamming",
+            RampFilter::Hann => "Hann",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSettings {
+    pub filter: RampFilter,
+    pub cutoff: f32, // as a fraction of the Nyquist frequency, in (0, 1]
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            filter: RampFilter::default(),
+            cutoff: 1.,
+        }
+    }
+}
+
+// Frequency response of the filter for rows padded to `len` samples.
+// The ramp is built from the FFT of the band-limited spatial kernel rather than
+// by sampling |f| directly, as the latter gives a DC offset in the result.
+fn frequency_response(settings: &FilterSettings, len: usize, fft: &Arc<dyn Fft<f32>>) -> Vec<f32> {
+    let mut kernel: Vec<Complex<f32>> = (0..len)
+        .map(|i| {
+            // distance from index 0, wrapping around such that the kernel is symmetric
+            let n = if i <= len/2 { i } else { len - i } as f32;
+
+            let value = if n == 0. {
+                0.25
+            } else if n as usize % 2 == 1 {
+                -1. / (PI*n).powi(2)
+            } else {
+                0.
+            };
+
+            Complex::new(value, 0.)
+        })
+        .collect();
+
+    fft.process(&mut kernel);
+
+    kernel.iter().enumerate()
+        .map(|(i, ramp)| {
+            let f = if i <= len/2 { i } else { len - i } as f32 / (len/2) as f32;
+
+            ramp.re * settings.filter.window(f, settings.cutoff)
+        })
+        .collect()
+}
+
+// Apply the ramp filter to each row of every projection in place.
+pub fn filter_projections(images: &mut [ScanImage], settings: &FilterSettings) {
+    if settings.filter == RampFilter::None || images.is_empty() {
+        return;
+    }
+
+    let width = images[0].width() as usize;
+
+    // zero-pad to at least twice the width to avoid wrap-around from the circular convolution
+    let len = (2*width).next_power_of_two();
+
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(len);
+    let ifft = planner.plan_fft_inverse(len);
+
+    let response = frequency_response(settings, len, &fft);
+
+    images.par_iter_mut().for_each(|image| {
+        let mut buffer = vec![Complex::new(0., 0.); len];
+        let mut scratch = vec![Complex::new(0., 0.); fft.get_inplace_scratch_len().max(ifft.get_inplace_scratch_len())];
+
+        for row in image.chunks_exact_mut(width) {
+            for (dst, src) in buffer.iter_mut().zip(row.iter()) {
+                *dst = Complex::new(*src, 0.);
+            }
+            buffer[width..].fill(Complex::new(0., 0.));
+
+            fft.process_with_scratch(&mut buffer, &mut scratch);
+            for (sample, gain) in buffer.iter_mut().zip(response.iter()) {
+                *sample *= *gain;
+            }
+            ifft.process_with_scratch(&mut buffer, &mut scratch);
+
+            // rustfft doesn't normalize the inverse transform
+            for (dst, src) in row.iter_mut().zip(buffer.iter()) {
+                *dst = src.re / len as f32;
+            }
+        }
+    });
+}
@@ -0,0 +1,132 @@
+use std::{io::{BufWriter, Write}, fs::File, path::PathBuf};
+
+use super::{calibration, preprocess::ProcessingSettings, reconstruction::{self, ReconstructionMode}, scan::{CtScan, LoadError}};
+
+const USAGE: &str = "usage: ct-rs --headless <scan descriptor> <output file> [voxels] [mode]";
+const CALIBRATE_USAGE: &str = "usage: ct-rs --calibrate <scan descriptor> <output descriptor> [bead distance]";
+
+// All the problems with a scan that couldn't be loaded, one per line
+fn load_error_message(errors: Vec<LoadError>) -> String {
+    errors.iter().fold(String::from("Error loading scan:"), |message, error| format!("{message}\n  {error}"))
+}
+
+fn load_scan(descriptor: &str) -> Result<CtScan, String> {
+    let runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+
+    runtime.block_on(CtScan::from_file(PathBuf::from(descriptor), |loaded, total| {
+        log::debug!("Loaded {loaded}/{total} images");
+    }))
+    .map_err(load_error_message)
+}
+
+// Reconstruct a scan on the CPU without opening a window, eg. on machines without a GPU.
+// The volume is written as raw little-endian f32s with x varying fastest, then y, then z.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let [descriptor, output, rest @ ..] = args else {
+        return Err(USAGE.into());
+    };
+
+    let (voxels, mode) = match rest {
+        [] => (None, None),
+        [voxels] => (Some(voxels), None),
+        [voxels, mode] => (Some(voxels), Some(mode)),
+        _ => return Err(USAGE.into()),
+    };
+
+    let resolution: u32 = match voxels {
+        None => 128,
+        Some(voxels) => voxels.parse().map_err(|_| format!("Invalid number of voxels: {voxels}"))?,
+    };
+
+    let mode = match mode {
+        None => ReconstructionMode::default(),
+        Some(mode) => ReconstructionMode::ALL.into_iter()
+            .find(|candidate| candidate.to_string().eq_ignore_ascii_case(mode))
+            .ok_or_else(|| format!("Unknown reconstruction mode: {mode}"))?,
+    };
+
+    let scan = load_scan(descriptor)?;
+
+    log::info!("Loaded scan {}, reconstructing {resolution}³ voxels with {mode}", scan.name);
+
+    // only for reference, the offset from the descriptor is used to reconstruct
+    if log::log_enabled!(log::Level::Info) {
+        if let Some(estimate) = calibration::estimate_detector_offset(&scan) {
+            log::info!("Estimated detector offset: {estimate:.2} mm (descriptor: {:.2} mm)", scan.detector_offset);
+        }
+    }
+
+    // reconstructing the previews takes a while, so the direction is only checked when the warning is logged
+    if log::log_enabled!(log::Level::Warn) {
+        if let Some(direction) = calibration::detect_rotation_direction(&scan) {
+            if direction != scan.direction {
+                log::warn!("The scan seems to rotate {direction:?}, but the descriptor says {:?}", scan.direction);
+            }
+        }
+    }
+
+    let settings = ProcessingSettings {
+        mode,
+        ..Default::default()
+    };
+    let volume = reconstruction::reconstruct(&scan, &settings, resolution, |iteration, _| {
+        log::info!("Finished iteration {iteration}");
+        true
+    });
+
+    let mut writer = BufWriter::new(File::create(output).map_err(|err| err.to_string())?);
+    for voxel in &volume.data {
+        writer.write_all(&voxel.to_le_bytes()).map_err(|err| err.to_string())?;
+    }
+    writer.flush().map_err(|err| err.to_string())?;
+
+    log::info!("Wrote {:?} to {output}", volume);
+
+    Ok(())
+}
+
+// Calibrate the geometry of a scan of a bead phantom and write it to a new scan descriptor,
+// which refers to the same projections
+pub fn calibrate(args: &[String]) -> Result<(), String> {
+    let (descriptor, output, bead_distance) = match args {
+        [descriptor, output] => (descriptor, output, None),
+        [descriptor, output, bead_distance] => (descriptor, output, Some(bead_distance)),
+        _ => return Err(CALIBRATE_USAGE.into()),
+    };
+
+    let bead_distance = bead_distance
+        .map(|distance| distance.parse::<f32>().map_err(|_| format!("Invalid bead distance: {distance}")))
+        .transpose()?;
+
+    let scan = load_scan(descriptor)?;
+
+    let calibration = calibration::beads::calibrate_geometry(&scan, bead_distance)?;
+    let mut calibrated = calibration.scan;
+
+    if bead_distance.is_none() {
+        eprintln!("Warning: no bead distance was given, so the sod of {:.2} mm from the descriptor is kept", calibrated.sod);
+    }
+
+    println!(
+        "Calibrated with {} beads, the error went from {:.2} to {:.2} pixels",
+        calibration.n_beads, calibration.initial_error, calibration.error
+    );
+    println!("sod: {:.2} mm, sdd: {:.2} mm", calibrated.sod, calibrated.sdd);
+    println!(
+        "detector offset: {:.3} mm, vertical offset: {:.3} mm",
+        calibrated.detector_offset, calibrated.detector_vertical_offset
+    );
+    println!(
+        "detector roll: {:.3}°, pitch: {:.3}°, yaw: {:.3}°",
+        calibrated.detector_roll, calibrated.detector_pitch, calibrated.detector_yaw
+    );
+
+    // the new descriptor can be written anywhere, so it refers to the projections with an absolute path
+    let images_dir = std::path::absolute(scan.images_dir()).map_err(|err| format!("Error resolving the projection directory: {err}"))?;
+    calibrated.projection_dir = Some(images_dir);
+
+    let json = serde_json::to_string_pretty(&calibrated).map_err(|err| err.to_string())?;
+    std::fs::write(output, json).map_err(|err| format!("Error writing {output}: {err}"))?;
+
+    Ok(())
+}
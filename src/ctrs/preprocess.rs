@@ -0,0 +1,466 @@
+pub mod bad_pixels;
+pub mod beam_hardening;
+pub mod denoise;
+mod ring_removal;
+
+use std::f32::consts::PI;
+
+use beam_hardening::BeamHardening;
+use denoise::DenoiseSettings;
+use image::ImageBuffer;
+use rayon::prelude::*;
+
+use super::{
+    filter::{self, FilterSettings},
+    reconstruction::{metal::MetalSettings, IterativeSettings, ReconstructionMode},
+    scan::{CtScan, PixelRegion, ScanImage},
+    scene::{pipeline::uniforms::Projection, projection_geometry},
+};
+
+// smallest transmission that is reconstructed, so zero and negative intensities have a finite attenuation
+const MIN_TRANSMISSION: f32 = 1e-4;
+// pixels that stay above this fraction of the brightest pixels in every projection are considered to be air
+const AIR_THRESHOLD: f32 = 0.95;
+// minimum number of pixels in an automatically detected air region
+const MIN_AIR_PIXELS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ProcessingSettings {
+    pub mode: ReconstructionMode,
+    pub filter: FilterSettings,
+    pub denoise: DenoiseSettings,
+    pub iterative: IterativeSettings,
+    pub metal: MetalSettings,
+    pub corrections: CorrectionSettings,
+}
+
+// corrections applied to the raw projections before they are reconstructed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorrectionSettings {
+    // scale each projection such that the intensity of air is one, to compensate for source drift
+    pub normalize_intensity: bool,
+    pub beam_hardening: BeamHardening,
+    // coefficient of the quadratic term for the manual beam hardening correction
+    pub beam_hardening_strength: f32,
+    pub remove_rings: bool,
+    // number of neighbouring detector columns that stripes are smoothed with
+    pub ring_removal_strength: u32,
+}
+
+impl Default for CorrectionSettings {
+    fn default() -> Self {
+        Self {
+            normalize_intensity: true,
+            beam_hardening: BeamHardening::default(),
+            beam_hardening_strength: 0.1,
+            remove_rings: false,
+            ring_removal_strength: 5,
+        }
+    }
+}
+
+// The transmitted intensities of each projection, before taking the logarithm.
+// This is the data used by the statistical reconstruction modes.
+pub fn transmission(scan: &CtScan, settings: &ProcessingSettings) -> Vec<ScanImage> {
+    let mut images = denoise::denoised_projections(scan, &settings.denoise).to_vec();
+
+    apply_flat_field(&mut images, scan);
+
+    if settings.corrections.normalize_intensity {
+        normalize_intensity(&mut images, scan.air_region);
+    }
+
+    clamp_transmission(&mut images);
+
+    if settings.corrections.remove_rings {
+        ring_removal::remove_rings(&mut images, settings.corrections.ring_removal_strength);
+    }
+
+    beam_hardening::linearize(&mut images, scan, settings);
+
+    images
+}
+
+// Pixels that only see air, either in the given region or the pixels that are bright in all projections
+fn air_mask(images: &[ScanImage], region: Option<PixelRegion>) -> Vec<bool> {
+    let (width, height) = images[0].dimensions();
+
+    if let Some(region) = region {
+        return (0..width*height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                (region.x..region.x + region.width).contains(&x) && (region.y..region.y + region.height).contains(&y)
+            })
+            .collect();
+    }
+
+    // the least intensity each pixel has received over the whole scan
+    let minimum = images.par_iter()
+        .fold(
+            || vec![f32::INFINITY; (width*height) as usize],
+            |mut minimum, image| {
+                for (min, sample) in minimum.iter_mut().zip(image.iter()) {
+                    *min = min.min(*sample);
+                }
+                minimum
+            }
+        )
+        .reduce(
+            || vec![f32::INFINITY; (width*height) as usize],
+            |a, b| a.into_iter().zip(b).map(|(a, b)| a.min(b)).collect()
+        );
+
+    // use a high percentile instead of the max so a few hot pixels don't matter
+    let mut finite: Vec<f32> = minimum.iter().copied().filter(|min| min.is_finite()).collect();
+    if finite.is_empty() {
+        return vec![false; minimum.len()];
+    }
+    let index = (finite.len() - 1) * 999 / 1000;
+    let brightest = *finite.select_nth_unstable_by(index, f32::total_cmp).1;
+
+    minimum.into_iter()
+        .map(|min| min.is_finite() && min >= AIR_THRESHOLD * brightest)
+        .collect()
+}
+
+// Divide each projection by the mean intensity of its air region (I0), so drift of
+// the source intensity between projections doesn't end up in the reconstruction.
+fn normalize_intensity(images: &mut [ScanImage], region: Option<PixelRegion>) {
+    if images.is_empty() {
+        return;
+    }
+
+    let mask = air_mask(images, region);
+    if mask.iter().filter(|air| **air).count() < MIN_AIR_PIXELS {
+        log::warn!("Not enough air in the projections to normalize their intensity");
+        return;
+    }
+
+    images.par_iter_mut().for_each(|image| {
+        let (sum, count) = image.iter().zip(&mask)
+            .filter(|(sample, air)| **air && sample.is_finite())
+            .fold((0., 0), |(sum, count), (sample, _)| (sum + sample, count + 1));
+
+        let intensity = sum / count as f32;
+        if count > 0 && intensity > 0. {
+            for sample in image.iter_mut() {
+                *sample /= intensity;
+            }
+        }
+    });
+}
+
+// Keep the transmission in (0, 1] so its logarithm is finite: zero and negative intensities are
+// clamped to a small transmission, while saturated pixels and values that aren't numbers are
+// treated as unattenuated.
+fn clamp_transmission(images: &mut [ScanImage]) {
+    images.par_iter_mut().for_each(|image| {
+        for sample in image.iter_mut() {
+            *sample = if sample.is_nan() {
+                1.
+            } else {
+                sample.clamp(MIN_TRANSMISSION, 1.)
+            };
+        }
+    });
+}
+
+// Flat-field correction: (I - D) / (F - D) with the averaged dark and flat images, which
+// removes the offset and gain differences between detector pixels. Without a flat image the
+// projections are assumed to already be relative to the open beam, so only the dark image is subtracted.
+fn apply_flat_field(images: &mut [ScanImage], scan: &CtScan) {
+    if scan.dark_image.is_none() && scan.flat_image.is_none() {
+        return;
+    }
+
+    let dark = scan.dark_image.as_deref();
+    let flat = scan.flat_image.as_deref();
+
+    images.par_iter_mut().for_each(|image| {
+        for (i, sample) in image.iter_mut().enumerate() {
+            let offset = dark.map_or(0., |dark| dark.as_raw()[i]);
+            let gain = flat.map_or(1., |flat| flat.as_raw()[i] - offset);
+
+            // pixels that don't respond to the beam carry no information, so treat them as unattenuated
+            *sample = if gain > 0. {
+                (*sample - offset) / gain
+            } else {
+                1.
+            };
+        }
+    });
+}
+
+// Turn the raw projection images of a scan into the data that is reconstructed:
+// line integrals (-ln of the transmission), normalized to a max of 1. For the
+// analytic modes the line integrals are also weighted and filtered before normalizing.
+pub fn preprocess(scan: &CtScan, settings: &ProcessingSettings) -> Vec<ScanImage> {
+    let mut images = line_integrals(scan, settings);
+
+    weight_and_filter(&mut images, scan, settings);
+
+    let max = max_sample(&images);
+    scale(&mut images, 1. / max);
+
+    images
+}
+
+// the corrected transmission of each projection turned into line integrals
+pub fn line_integrals(scan: &CtScan, settings: &ProcessingSettings) -> Vec<ScanImage> {
+    let mut images = transmission(scan, settings);
+
+    images.par_iter_mut().for_each(|image| {
+        for sample in image.iter_mut() {
+            *sample = -sample.ln();
+        }
+    });
+
+    images
+}
+
+// weight and filter line integrals as needed for the analytic modes
+pub fn weight_and_filter(images: &mut [ScanImage], scan: &CtScan, settings: &ProcessingSettings) {
+    if settings.mode.is_cone_beam_weighted() {
+        apply_cosine_weights(images, scan);
+    }
+
+    if !settings.mode.is_iterative() {
+        apply_parker_weights(images, scan);
+        filter::filter_projections(images, &settings.filter);
+    }
+}
+
+// the largest finite sample in the images, or 1 if there is none larger than 0
+pub fn max_sample(images: &[ScanImage]) -> f32 {
+    let max = images.par_iter()
+        .map(|image| image.iter().copied().filter(|sample| sample.is_finite()).fold(f32::MIN, f32::max))
+        .reduce(|| f32::MIN, f32::max);
+
+    if max > 0. { max } else { 1. }
+}
+
+pub fn scale(images: &mut [ScanImage], factor: f32) {
+    images.par_iter_mut().for_each(|image| {
+        for sample in image.iter_mut() {
+            *sample *= factor;
+        }
+    });
+}
+
+// FDK pre-weighting: scale each detector pixel by the cosine of the angle
+// between its ray and the central ray, sdd / sqrt(sdd² + u² + v²).
+fn apply_cosine_weights(images: &mut [ScanImage], scan: &CtScan) {
+    let projections = projection_geometry(scan);
+
+    images.par_iter_mut().zip(projections.par_iter()).for_each(|(image, projection)| {
+        let (width, height) = image.dimensions();
+        // the source in sensor-plane coordinates, its y coordinate is its distance from the detector plane
+        let source = projection.source;
+        let distance = source[1].abs();
+
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            // position of the pixel center on the detector, relative to where the central ray hits it,
+            // with the same pitch and offsets as the projector
+            let point = projection.texture_to_projection([(x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32]);
+            let (u, v) = (point[0] - source[0], point[1] - source[2]);
+
+            pixel.0[0] *= distance / (distance.powi(2) + u.powi(2) + v.powi(2)).sqrt();
+        }
+    });
+}
+
+// Half of the angle of the fan of rays from the source to the detector, in radians
+fn half_fan_angle(projection: &Projection) -> f32 {
+    let half_width = f32::max(
+        projection.texture_to_projection([0., 0.5])[0].abs(),
+        projection.texture_to_projection([1., 0.5])[0].abs(),
+    );
+
+    (half_width / projection.sdd).atan()
+}
+
+// Parker weight of the ray with fan angle `gamma` in the projection taken `beta` after the first one,
+// for a scan that sweeps 180° plus twice `overscan` (all in radians)
+fn parker_weight(beta: f32, gamma: f32, overscan: f32) -> f32 {
+    if beta < 2.*(overscan - gamma) {
+        (PI/4. * beta / (overscan - gamma)).sin().powi(2)
+    } else if beta <= PI - 2.*gamma {
+        1.
+    } else if beta <= PI + 2.*overscan {
+        (PI/4. * (PI + 2.*overscan - beta) / (overscan + gamma)).sin().powi(2)
+    } else {
+        0.
+    }
+}
+
+// Parker weights for short scans (Parker, "Optimal short scan convolution reconstruction for fanbeam CT", 1982).
+// A scan covering less than a full rotation measures some rays twice and others only once, so the
+// analytic reconstruction would be brighter where rays are measured twice. The weights smoothly fade
+// the redundant rays in and out such that the weights of each ray and its opposite ray sum to one.
+// The weights are generalized to sweeps longer than 180° plus the fan angle by using the actual overscan
+// in place of the fan angle (Wesarg et al., "Parker weights revisited", 2002).
+fn apply_parker_weights(images: &mut [ScanImage], scan: &CtScan) {
+    // the sweep of the projections themselves, as the swept angle is negative for reverse scans
+    // and doesn't apply to explicit angles
+    let sweep = scan.sweep();
+    if sweep >= 360. - 1e-3 || images.is_empty() {
+        return;
+    }
+
+    let projections = projection_geometry(scan);
+    let half_fan = half_fan_angle(&projections[0]);
+
+    if sweep.to_radians() < PI + 2.*half_fan {
+        log::warn!(
+            "Scan only sweeps {sweep:.1}°, but a short scan needs at least {:.1}° to be reconstructed without artifacts",
+            180. + 2.*half_fan.to_degrees(),
+        );
+    }
+
+    log::info!("Short scan of {sweep:.1}°, applying Parker weights");
+
+    // half of the angle swept in addition to 180°
+    let overscan = ((sweep.to_radians() - PI) / 2.).max(f32::EPSILON);
+    let angles = scan.projection_angles();
+    // the turntable rotates the other way when the angles decrease
+    let rotation = if angles[angles.len() - 1] < angles[0] { -scan.direction.dir() } else { scan.direction.dir() };
+
+    images.par_iter_mut().zip(projections.par_iter()).zip(&angles).for_each(|((image, projection), angle)| {
+        // angle rotated since the first projection
+        let beta = (angle - angles[0]).abs().to_radians();
+        let width = image.width();
+
+        for (x, _, pixel) in image.enumerate_pixels_mut() {
+            // the fan angle of the pixel, signed such that it increases with the rotation
+            let u = projection.texture_to_projection([(x as f32 + 0.5) / width as f32, 0.5])[0];
+            let gamma = rotation * (u / projection.sdd).atan();
+
+            pixel.0[0] *= parker_weight(beta, gamma, overscan);
+        }
+    });
+}
+
+// Binning factor that reduces images of the given dimensions to roughly `width` pixels wide. The factor
+// must divide the image dimensions to keep the texture coordinates of the binned pixels the same.
+pub fn bin_factor(dimensions: (u32, u32), width: u32) -> u32 {
+    (1..=dimensions.0.div_ceil(width))
+        .rev()
+        .find(|factor| dimensions.0.is_multiple_of(*factor) && dimensions.1.is_multiple_of(*factor))
+        .unwrap_or(1)
+}
+
+// Average blocks of `factor`×`factor` pixels to reduce the size of the images.
+// The factor should divide the image dimensions, otherwise the remaining pixels are dropped.
+pub fn bin(images: &[ScanImage], factor: u32) -> Vec<ScanImage> {
+    if factor <= 1 {
+        return images.to_vec();
+    }
+
+    images.par_iter()
+        .map(|image| {
+            let (width, height) = (image.width() / factor, image.height() / factor);
+
+            ImageBuffer::from_fn(width, height, |x, y| {
+                let mut sum = 0.;
+                for dy in 0..factor {
+                    for dx in 0..factor {
+                        sum += image.get_pixel(x*factor + dx, y*factor + dy).0[0];
+                    }
+                }
+
+                [sum / (factor*factor) as f32].into()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use crate::ctrs::scan::{ProjectionFile, RotationDirection};
+
+    use super::*;
+
+    const N_PROJECTIONS: usize = 60;
+
+    // a scan with evenly spaced projections, or explicit angles if they are given
+    fn scan(direction: RotationDirection, swept_angle: f32, angles: Option<&[f32]>) -> CtScan {
+        let mut scan: CtScan = serde_json::from_value(serde_json::json!({
+            "name": "Parker",
+            "direction": direction,
+            "sod": 200.,
+            "sdd": 300.,
+            "swept_angle": swept_angle,
+            "pixel_size": 1.,
+            "projections": [],
+        }))
+        .unwrap();
+
+        scan.projection_files = (0..N_PROJECTIONS)
+            .map(|i| match angles {
+                Some(angles) => ProjectionFile::WithAngle { file: format!("{i}.tif").into(), angle: angles[i] },
+                None => ProjectionFile::Path(format!("{i}.tif").into()),
+            })
+            .collect();
+        scan.projection_images = vec![ScanImage::from_pixel(33, 4, Luma([1.])); N_PROJECTIONS].into();
+        scan
+    }
+
+    fn parker_weights(scan: &CtScan) -> Vec<ScanImage> {
+        let mut images = scan.projection_images.to_vec();
+        apply_parker_weights(&mut images, scan);
+        images
+    }
+
+    #[test]
+    fn parker_weights_of_opposing_rays_sum_to_one() {
+        let half_fan = 0.15;
+
+        // a sweep of exactly 180° plus the fan angle, and one with more overscan
+        for overscan in [half_fan, half_fan + 0.2] {
+            for i in 0..=100 {
+                let beta = i as f32 / 100. * (PI + 2.*overscan);
+                // the outermost rays of the fan are only measured at a single angle
+                for j in 1..10 {
+                    let gamma = (j as f32 / 5. - 1.) * half_fan;
+
+                    // the same ray is measured again half a turn later, from the other side of the fan
+                    let opposing = beta + PI + 2.*gamma;
+                    if opposing > PI + 2.*overscan {
+                        continue;
+                    }
+
+                    let sum = parker_weight(beta, gamma, overscan) + parker_weight(opposing, -gamma, overscan);
+                    assert!((sum - 1.).abs() < 1e-4, "{sum} at beta {beta}, gamma {gamma}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn full_scans_are_not_parker_weighted() {
+        for swept_angle in [360., -360.] {
+            let scan = scan(RotationDirection::CW, swept_angle, None);
+
+            assert!(parker_weights(&scan).iter().flat_map(|image| image.iter()).all(|weight| *weight == 1.));
+        }
+    }
+
+    #[test]
+    fn reverse_and_explicit_short_scans_are_parker_weighted_like_forward_ones() {
+        let forward = parker_weights(&scan(RotationDirection::CW, 200., None));
+        assert!(forward.iter().flat_map(|image| image.iter()).any(|weight| *weight < 0.5));
+
+        // turning the other way with negative angles is the same rotation
+        let reverse = parker_weights(&scan(RotationDirection::CCW, -200., None));
+        let angles: Vec<f32> = (0..N_PROJECTIONS).map(|i| i as f32 * 200. / N_PROJECTIONS as f32).collect();
+        let explicit = parker_weights(&scan(RotationDirection::CW, 0., Some(&angles)));
+
+        for other in [reverse, explicit] {
+            for (a, b) in forward.iter().flat_map(|image| image.iter()).zip(other.iter().flat_map(|image| image.iter())) {
+                assert!((a - b).abs() < 1e-5, "{a} != {b}");
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use rayon::prelude::*;
+
+use crate::ctrs::scan::ScanImage;
+
+// constant pixels that differ by more than this fraction from their neighbours are bad
+const MIN_CONTRAST: f32 = 0.1;
+// max distance (in pixels) of the good pixels that a bad pixel is interpolated from
+const MAX_RADIUS: i64 = 4;
+
+// Median of the 8 neighbours of a pixel that are inside the image, or None if the image is a single pixel
+fn neighbour_median(values: &[f32], width: i64, height: i64, x: i64, y: i64) -> Option<f32> {
+    let mut neighbours: Vec<f32> = (-1..=1)
+        .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(|&(nx, ny)| (nx, ny) != (x, y) && (0..width).contains(&nx) && (0..height).contains(&ny))
+        .map(|(nx, ny)| values[(ny*width + nx) as usize])
+        .collect();
+
+    if neighbours.is_empty() {
+        return None;
+    }
+
+    let middle = neighbours.len() / 2;
+    Some(*neighbours.select_nth_unstable_by(middle, f32::total_cmp).1)
+}
+
+// Find dead and hot pixels, which have the same value in all projections. Pixels that only see air
+// or are saturated can be constant as well, so a constant pixel is only bad if it stands out from
+// its neighbours.
+pub fn detect(images: &[ScanImage]) -> Vec<bool> {
+    let Some(first) = images.first() else {
+        return Vec::new();
+    };
+
+    let (width, height) = first.dimensions();
+    let len = (width*height) as usize;
+    if images.len() < 2 {
+        return vec![false; len];
+    }
+
+    // the least and greatest value and the sum of each pixel over all projections
+    let (minimum, maximum, sum) = images.par_iter()
+        .fold(
+            || (vec![f32::INFINITY; len], vec![f32::NEG_INFINITY; len], vec![0f32; len]),
+            |(mut minimum, mut maximum, mut sum), image| {
+                for (i, sample) in image.iter().enumerate() {
+                    minimum[i] = minimum[i].min(*sample);
+                    maximum[i] = maximum[i].max(*sample);
+                    sum[i] += sample;
+                }
+                (minimum, maximum, sum)
+            }
+        )
+        .reduce(
+            || (vec![f32::INFINITY; len], vec![f32::NEG_INFINITY; len], vec![0f32; len]),
+            |(mut minimum, mut maximum, mut sum), (other_minimum, other_maximum, other_sum)| {
+                for i in 0..len {
+                    minimum[i] = minimum[i].min(other_minimum[i]);
+                    maximum[i] = maximum[i].max(other_maximum[i]);
+                    sum[i] += other_sum[i];
+                }
+                (minimum, maximum, sum)
+            }
+        );
+
+    let (width, height) = (width as i64, height as i64);
+    (0..len)
+        .map(|i| {
+            if minimum[i] != maximum[i] {
+                return false;
+            }
+
+            // a pixel without neighbours can't stand out from them
+            let Some(neighbours) = neighbour_median(&sum, width, height, i as i64 % width, i as i64 / width) else {
+                return false;
+            };
+            (sum[i] - neighbours).abs() > MIN_CONTRAST * neighbours.abs()
+        })
+        .collect()
+}
+
+// Replace the bad pixels of an image by the mean of the closest good pixels around them
+pub fn correct(image: &mut ScanImage, mask: &[bool]) {
+    if !mask.contains(&true) {
+        return;
+    }
+
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let original = image.clone();
+
+    for (i, _) in mask.iter().enumerate().filter(|(_, bad)| **bad) {
+        let (x, y) = (i as i64 % width, i as i64 / width);
+
+        // grow the neighbourhood until it contains good pixels
+        let replacement = (1..=MAX_RADIUS).find_map(|radius| {
+            let good: Vec<f32> = (-radius..=radius)
+                .flat_map(|dy| (-radius..=radius).map(move |dx| (x + dx, y + dy)))
+                .filter(|&(nx, ny)| (0..width).contains(&nx) && (0..height).contains(&ny))
+                .filter(|&(nx, ny)| !mask[(ny*width + nx) as usize])
+                .map(|(nx, ny)| original.get_pixel(nx as u32, ny as u32).0[0])
+                .collect();
+
+            (!good.is_empty()).then(|| good.iter().sum::<f32>() / good.len() as f32)
+        });
+
+        if let Some(replacement) = replacement {
+            image.get_pixel_mut(x as u32, y as u32).0[0] = replacement;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    #[test]
+    fn detects_and_corrects_a_stuck_pixel() {
+        // a gradient that brightens from one projection to the next, with a dead pixel at (2, 1)
+        let mut images: Vec<ScanImage> = (0..3)
+            .map(|i| ScanImage::from_fn(5, 4, |x, y| Luma([0.5 + 0.1 * x as f32 + 0.01 * y as f32 + 0.05 * i as f32])))
+            .collect();
+        for image in &mut images {
+            image.put_pixel(2, 1, Luma([0.]));
+        }
+
+        let mask = detect(&images);
+        let bad: Vec<usize> = mask.iter().enumerate().filter(|(_, bad)| **bad).map(|(i, _)| i).collect();
+        assert_eq!(bad, vec![5 + 2]);
+
+        correct(&mut images[0], &mask);
+        // the mean of the 8 pixels around it, which is the value of the gradient there
+        assert!((images[0].get_pixel(2, 1).0[0] - 0.71).abs() < 1e-5, "{}", images[0].get_pixel(2, 1).0[0]);
+    }
+
+    #[test]
+    fn a_single_pixel_is_never_bad() {
+        let images = vec![ScanImage::from_pixel(1, 1, Luma([0.])); 3];
+
+        assert_eq!(detect(&images), vec![false]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{fmt::Display, sync::Mutex};
+
+use rayon::prelude::*;
+
+use crate::ctrs::{
+    filter::{self, FilterSettings},
+    reconstruction::{backprojection, forward_projection::forward_project_like},
+    scan::{CtScan, ScanImage},
+    scene::{pipeline::uniforms::Projection, projection_geometry, VOLUME_SIZE},
+};
+
+use super::{apply_cosine_weights, bin, bin_factor, ProcessingSettings};
+
+// voxels along each axis of the preview reconstruction the automatic correction is fitted to
+const PREVIEW_RESOLUTION: u32 = 64;
+// max number of projections the automatic correction is fitted to
+const FIT_PROJECTIONS: usize = 32;
+// rays shorter than this fraction of the longest ray through the object aren't used in the fit
+const MIN_FIT_LENGTH: f32 = 0.25;
+// voxels above this fraction of the brightest voxels in the preview are part of the object
+const OBJECT_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BeamHardening {
+    None,
+    // the polynomial given in the scan descriptor
+    #[default]
+    Descriptor,
+    // p + strength·p²
+    Manual,
+    // a polynomial fitted such that the object is reconstructed as if it was homogeneous
+    Automatic,
+}
+
+impl BeamHardening {
+    pub const ALL: [BeamHardening; 4] = [
+        BeamHardening::None,
+        BeamHardening::Descriptor,
+        BeamHardening::Manual,
+        BeamHardening::Automatic,
+    ];
+}
+
+impl Display for BeamHardening {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BeamHardening::None => "None",
+            BeamHardening::Descriptor => "Descriptor",
+            BeamHardening::Manual => "Manual",
+            BeamHardening::Automatic => "Automatic",
+        })
+    }
+}
+
+// What the automatic correction depends on: the settings of the steps before it and the geometry of the scan
+#[derive(Debug, Clone, PartialEq)]
+struct FitKey {
+    settings: ProcessingSettings,
+    projections: Vec<Projection>,
+}
+
+// The last automatic correction fitted to a scan (None if the fit failed), so it is only fitted again
+// when the projections or the geometry change.
+#[derive(Default)]
+pub struct FitCache(Mutex<Option<(FitKey, Option<Vec<f32>>)>>);
+
+// value of the polynomial c₀p + c₁p² + c₂p³ + ...
+fn evaluate(coefficients: &[f32], p: f32) -> f32 {
+    coefficients.iter().rev().fold(0., |sum, coefficient| (sum + coefficient) * p)
+}
+
+// Solve the 3x3 linear system a·x = b with Gaussian elimination, or None if it is singular
+fn solve(mut a: [[f64; 3]; 3], mut b: [f64; 3]) -> Option<[f64; 3]> {
+    for col in 0..3 {
+        let pivot = (col..3).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in col+1..3 {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (element, pivot_element) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                *element -= factor * pivot_element;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = [0.; 3];
+    for row in (0..3).rev() {
+        let sum: f64 = (row+1..3).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+
+    Some(x)
+}
+
+// Fit a cubic polynomial to the line integrals of a scan, such that they become proportional to the
+// length of each ray through the object, as they would be for a homogeneous object and a monochromatic
+// beam. The object is segmented from a quick FDK reconstruction of the uncorrected line integrals, and
+// the lengths are found by forward projecting it. Returns None if no useful polynomial was found.
+fn fit(line_integrals: &[ScanImage], scan: &CtScan) -> Option<Vec<f32>> {
+    let projections = projection_geometry(scan);
+    let factor = bin_factor(line_integrals[0].dimensions(), 2*PREVIEW_RESOLUTION);
+
+    let mut weighted = line_integrals.to_vec();
+    apply_cosine_weights(&mut weighted, scan);
+    let mut filtered = bin(&weighted, factor);
+    filter::filter_projections(&mut filtered, &FilterSettings::default());
+
+    let dimensions = (PREVIEW_RESOLUTION, PREVIEW_RESOLUTION, PREVIEW_RESOLUTION);
+    let mut object = backprojection::backproject(&filtered, &projections, dimensions, VOLUME_SIZE, true);
+
+    // use a high percentile instead of the max so a few bright voxels don't matter
+    let mut sorted = object.data.clone();
+    let index = (sorted.len() - 1) * 99 / 100;
+    let bright = *sorted.select_nth_unstable_by(index, f32::total_cmp).1;
+    if bright <= 0. {
+        return None;
+    }
+
+    object.data.par_iter_mut().for_each(|voxel| {
+        *voxel = if *voxel > OBJECT_THRESHOLD * bright { 1. } else { 0. };
+    });
+
+    let binned = bin(line_integrals, factor);
+    let stride = binned.len().div_ceil(FIT_PROJECTIONS);
+
+    // the normal equations of the least squares fit of c₀p + c₁p² + c₂p³ to the ray lengths
+    let (ata, atb) = binned.par_iter().zip(projections.par_iter()).step_by(stride)
+        .map(|(image, projection)| {
+            let lengths = forward_project_like(&object, projection, image);
+            let max_length = lengths.iter().copied().fold(0., f32::max);
+
+            let mut ata = [[0f64; 3]; 3];
+            let mut atb = [0f64; 3];
+
+            // rays that only graze the object are too sensitive to errors in the segmentation
+            let rays = image.iter().zip(lengths.iter())
+                .filter(|(_, length)| **length >= MIN_FIT_LENGTH * max_length);
+            for (p, length) in rays {
+                let powers = [*p as f64, (*p as f64).powi(2), (*p as f64).powi(3)];
+                for row in 0..3 {
+                    for col in 0..3 {
+                        ata[row][col] += powers[row] * powers[col];
+                    }
+                    atb[row] += powers[row] * *length as f64;
+                }
+            }
+
+            (ata, atb)
+        })
+        .reduce(
+            || ([[0.; 3]; 3], [0.; 3]),
+            |(mut ata, mut atb), (other_ata, other_atb)| {
+                for row in 0..3 {
+                    for col in 0..3 {
+                        ata[row][col] += other_ata[row][col];
+                    }
+                    atb[row] += other_atb[row];
+                }
+                (ata, atb)
+            }
+        );
+
+    let solution = solve(ata, atb)?;
+    if solution[0] <= 0. {
+        return None;
+    }
+
+    // scale the polynomial such that it doesn't change small line integrals
+    Some(solution.iter().map(|c| (c / solution[0]) as f32).collect())
+}
+
+// Whether a polynomial keeps larger line integrals larger, up to the given line integral
+fn is_increasing(coefficients: &[f32], max: f32) -> bool {
+    (0..=100)
+        .map(|i| i as f32 / 100. * max)
+        .all(|p| {
+            let derivative: f32 = coefficients.iter().enumerate()
+                .map(|(i, coefficient)| (i + 1) as f32 * coefficient * p.powi(i as i32))
+                .sum();
+            derivative > 0.
+        })
+}
+
+// The automatic correction for a scan, fitted again only if the settings or the geometry have changed
+fn cached_fit(images: &[ScanImage], scan: &CtScan, settings: &ProcessingSettings) -> Option<Vec<f32>> {
+    // the settings that only affect the reconstruction don't change the fit
+    let key = FitKey {
+        settings: ProcessingSettings {
+            denoise: settings.denoise,
+            corrections: settings.corrections,
+            ..Default::default()
+        },
+        projections: projection_geometry(scan).to_vec(),
+    };
+
+    // keep the cache locked while fitting, so the same correction isn't fitted twice at once
+    let mut cache = scan.beam_hardening_fit.0.lock().unwrap();
+    if let Some((cached_key, coefficients)) = cache.as_ref() {
+        if *cached_key == key {
+            return coefficients.clone();
+        }
+    }
+
+    let line_integrals: Vec<ScanImage> = images.par_iter()
+        .map(|image| {
+            let mut image = image.clone();
+            image.iter_mut().for_each(|sample| *sample = -sample.ln());
+            image
+        })
+        .collect();
+
+    let coefficients = fit(&line_integrals, scan);
+    *cache = Some((key, coefficients.clone()));
+
+    coefficients
+}
+
+// Linearize the line integrals (-ln of the transmission) of the projections with a polynomial,
+// to remove the cupping that the polychromatic beam causes.
+pub fn linearize(images: &mut [ScanImage], scan: &CtScan, settings: &ProcessingSettings) {
+    if images.is_empty() {
+        return;
+    }
+
+    let corrections = &settings.corrections;
+    let coefficients = match corrections.beam_hardening {
+        BeamHardening::None => return,
+        BeamHardening::Descriptor if scan.beam_hardening.is_empty() => return,
+        BeamHardening::Descriptor => scan.beam_hardening.clone(),
+        BeamHardening::Manual => vec![1., corrections.beam_hardening_strength],
+        BeamHardening::Automatic => match cached_fit(images, scan, settings) {
+            Some(coefficients) => coefficients,
+            None => {
+                log::warn!("Couldn't fit a beam hardening correction to {}", scan.name);
+                return;
+            },
+        },
+    };
+
+    // the correction has to keep larger line integrals larger over the measured range
+    let max = images.par_iter()
+        .map(|image| image.iter().map(|sample| -sample.ln()).fold(0., f32::max))
+        .reduce(|| 0., f32::max);
+    if !is_increasing(&coefficients, max) {
+        log::warn!(
+            "The beam hardening polynomial {coefficients:?} doesn't increase over the measured line integrals, \
+            so {} isn't corrected for beam hardening",
+            scan.name
+        );
+        return;
+    }
+
+    log::info!("Correcting beam hardening with the polynomial {coefficients:?}");
+
+    images.par_iter_mut().for_each(|image| {
+        for sample in image.iter_mut() {
+            *sample = (-evaluate(&coefficients, -sample.ln())).exp();
+        }
+    });
+}
@@ -0,0 +1,282 @@
+use std::{fmt::Display, sync::{Arc, Mutex}};
+
+use image::Luma;
+use rayon::prelude::*;
+
+use crate::ctrs::scan::{CtScan, ScanImage};
+
+// max number of filters in the denoising chain
+pub const MAX_STEPS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DenoiseFilter {
+    #[default]
+    None,
+    // median of the square window around each pixel, removes outliers
+    Median,
+    Gaussian,
+    // Gaussian that only averages pixels of a similar intensity, which keeps edges sharp
+    Bilateral,
+    // average of the pixels in a search window, weighted by how similar the patches around them are
+    NonLocalMeans,
+}
+
+impl DenoiseFilter {
+    pub const ALL: [DenoiseFilter; 5] = [
+        DenoiseFilter::None,
+        DenoiseFilter::Median,
+        DenoiseFilter::Gaussian,
+        DenoiseFilter::Bilateral,
+        DenoiseFilter::NonLocalMeans,
+    ];
+
+    // whether the size is a window radius, which only takes whole numbers of pixels
+    pub fn has_integer_size(&self) -> bool {
+        matches!(self, DenoiseFilter::Median | DenoiseFilter::NonLocalMeans)
+    }
+}
+
+impl Display for DenoiseFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DenoiseFilter::None => "None",
+            DenoiseFilter::Median => "Median",
+            DenoiseFilter::Gaussian => "Gaussian",
+            DenoiseFilter::Bilateral => "Bilateral",
+            DenoiseFilter::NonLocalMeans => "Non-local means",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DenoiseStep {
+    pub filter: DenoiseFilter,
+    // radius of the median window and the non-local means search window,
+    // or the standard deviation of the (bilateral) Gaussian, in pixels
+    pub size: f32,
+}
+
+impl Default for DenoiseStep {
+    fn default() -> Self {
+        Self {
+            filter: DenoiseFilter::None,
+            size: 1.,
+        }
+    }
+}
+
+// filters applied to the raw projections, in order
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DenoiseSettings {
+    pub steps: [DenoiseStep; MAX_STEPS],
+}
+
+impl DenoiseSettings {
+    pub fn is_empty(&self) -> bool {
+        self.steps.iter().all(|step| step.filter == DenoiseFilter::None)
+    }
+}
+
+// The denoised projections of a scan with the settings they were denoised with,
+// so they are only denoised again when the denoising settings change.
+#[derive(Default)]
+pub struct DenoiseCache(Mutex<Option<(DenoiseSettings, Arc<[ScanImage]>)>>);
+
+// Value of the pixel at (x, y), where coordinates outside of the image are clamped to its edges
+fn clamped(image: &ScanImage, x: i64, y: i64) -> f32 {
+    let x = x.clamp(0, image.width() as i64 - 1) as u32;
+    let y = y.clamp(0, image.height() as i64 - 1) as u32;
+
+    image.get_pixel(x, y).0[0]
+}
+
+// Estimate the standard deviation of the noise in an image from the median absolute
+// difference between horizontal neighbours, which is hardly affected by edges.
+fn noise_level(image: &ScanImage) -> f32 {
+    let mut differences: Vec<f32> = image.rows()
+        .flat_map(|row| {
+            let row: Vec<f32> = row.map(|pixel| pixel.0[0]).collect();
+            row.windows(2).map(|pair| (pair[1] - pair[0]).abs()).collect::<Vec<f32>>()
+        })
+        .collect();
+
+    if differences.is_empty() {
+        return 0.;
+    }
+
+    let middle = differences.len() / 2;
+    let median = *differences.select_nth_unstable_by(middle, f32::total_cmp).1;
+
+    // the difference of two pixels has √2 times the noise of one, and the median of its absolute
+    // value is 0.6745 standard deviations for Gaussian noise
+    median / (0.6745 * std::f32::consts::SQRT_2)
+}
+
+fn median(image: &ScanImage, radius: i64) -> ScanImage {
+    let mut window = Vec::with_capacity(((2*radius + 1) * (2*radius + 1)) as usize);
+
+    ScanImage::from_fn(image.width(), image.height(), |x, y| {
+        window.clear();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                window.push(clamped(image, x as i64 + dx, y as i64 + dy));
+            }
+        }
+
+        let middle = window.len() / 2;
+        Luma([*window.select_nth_unstable_by(middle, f32::total_cmp).1])
+    })
+}
+
+// Separable Gaussian blur, the kernel extends to three standard deviations
+fn gaussian(image: &ScanImage, sigma: f32) -> ScanImage {
+    let radius = (3. * sigma).ceil() as i64;
+    let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i*i) as f32 / (2. * sigma*sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+
+    let horizontal = ScanImage::from_fn(image.width(), image.height(), |x, y| {
+        let sum: f32 = (-radius..=radius).zip(&kernel)
+            .map(|(i, weight)| weight * clamped(image, x as i64 + i, y as i64))
+            .sum();
+        Luma([sum / total])
+    });
+
+    ScanImage::from_fn(image.width(), image.height(), |x, y| {
+        let sum: f32 = (-radius..=radius).zip(&kernel)
+            .map(|(i, weight)| weight * clamped(&horizontal, x as i64, y as i64 + i))
+            .sum();
+        Luma([sum / total])
+    })
+}
+
+// Bilateral filter, where pixels that differ by much more than the noise level don't contribute
+fn bilateral(image: &ScanImage, sigma: f32) -> ScanImage {
+    let range_sigma = 2. * noise_level(image);
+    if range_sigma <= 0. {
+        return image.clone();
+    }
+
+    let radius = (2. * sigma).ceil() as i64;
+
+    ScanImage::from_fn(image.width(), image.height(), |x, y| {
+        let center = image.get_pixel(x, y).0[0];
+
+        let (mut sum, mut total) = (0., 0.);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let value = clamped(image, x as i64 + dx, y as i64 + dy);
+                let distance = (dx*dx + dy*dy) as f32 / (2. * sigma*sigma);
+                let difference = (value - center).powi(2) / (2. * range_sigma*range_sigma);
+                let weight = (-distance - difference).exp();
+
+                sum += weight * value;
+                total += weight;
+            }
+        }
+
+        Luma([sum / total])
+    })
+}
+
+// Non-local means with 3x3 patches (Buades et al.). The squared differences between the image and
+// each shifted copy of it are summed over the patches with a box filter, so every offset in the
+// search window costs about the same as a single pass over the image.
+fn non_local_means(image: &ScanImage, search_radius: i64) -> ScanImage {
+    let sigma = noise_level(image);
+    if sigma <= 0. {
+        return image.clone();
+    }
+
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let len = (width * height) as usize;
+    // filtering strength, relative to the noise level
+    let h2 = (0.4 * sigma).powi(2);
+
+    let mut sums = vec![0f32; len];
+    let mut totals = vec![0f32; len];
+    let mut differences = vec![0f32; len];
+    let mut rows = vec![0f32; len];
+
+    // each offset is a pass over the whole image, which is split into rows that are filtered in parallel
+    let at = |values: &[f32], x: i64, y: i64| values[(y.clamp(0, height - 1)*width + x.clamp(0, width - 1)) as usize];
+    for dy in -search_radius..=search_radius {
+        for dx in -search_radius..=search_radius {
+            differences.par_chunks_exact_mut(width as usize).enumerate().for_each(|(y, row)| {
+                let y = y as i64;
+                for (x, difference) in (0..width).zip(row) {
+                    *difference = (image.get_pixel(x as u32, y as u32).0[0] - clamped(image, x + dx, y + dy)).powi(2);
+                }
+            });
+
+            // the mean squared difference over the 3x3 patch around each pixel
+            rows.par_chunks_exact_mut(width as usize).enumerate().for_each(|(y, row)| {
+                for (x, sum) in (0..width).zip(row) {
+                    *sum = (-1..=1).map(|i| at(&differences, x + i, y as i64)).sum();
+                }
+            });
+
+            sums.par_chunks_exact_mut(width as usize)
+                .zip(totals.par_chunks_exact_mut(width as usize))
+                .enumerate()
+                .for_each(|(y, (sums, totals))| {
+                    let y = y as i64;
+                    for x in 0..width {
+                        let distance = (-1..=1).map(|i| at(&rows, x, y + i)).sum::<f32>() / 9.;
+                        // patches that only differ by the noise get the full weight
+                        let weight = (-(distance - 2.*sigma*sigma).max(0.) / h2).exp();
+
+                        sums[x as usize] += weight * clamped(image, x + dx, y + dy);
+                        totals[x as usize] += weight;
+                    }
+                });
+        }
+    }
+
+    ScanImage::from_fn(image.width(), image.height(), |x, y| {
+        let i = (y as i64 * width + x as i64) as usize;
+        Luma([sums[i] / totals[i]])
+    })
+}
+
+fn apply(image: &ScanImage, step: &DenoiseStep) -> ScanImage {
+    let radius = (step.size.round() as i64).max(1);
+
+    match step.filter {
+        DenoiseFilter::None => image.clone(),
+        DenoiseFilter::Median => median(image, radius),
+        DenoiseFilter::Gaussian => gaussian(image, step.size),
+        DenoiseFilter::Bilateral => bilateral(image, step.size),
+        DenoiseFilter::NonLocalMeans => non_local_means(image, radius),
+    }
+}
+
+// The projections of a scan after applying the chain of denoising filters to each of them.
+// The result is cached, so it is only computed again when the settings change.
+pub fn denoised_projections(scan: &CtScan, settings: &DenoiseSettings) -> Arc<[ScanImage]> {
+    if settings.is_empty() {
+        return scan.projection_images.clone();
+    }
+
+    // keep the cache locked while denoising, so the same settings aren't denoised twice at once
+    let mut cache = scan.denoised.0.lock().unwrap();
+    if let Some((cached_settings, images)) = cache.as_ref() {
+        if cached_settings == settings {
+            return images.clone();
+        }
+    }
+
+    log::info!("Denoising the projections of {}", scan.name);
+
+    let images: Arc<[ScanImage]> = scan.projection_images.par_iter()
+        .map(|image| {
+            settings.steps.iter()
+                .filter(|step| step.filter != DenoiseFilter::None)
+                .fold(image.clone(), |image, step| apply(&image, step))
+        })
+        .collect::<Vec<_>>()
+        .into();
+
+    *cache = Some((*settings, images.clone()));
+
+    images
+}
@@ -0,0 +1,119 @@
+use rayon::prelude::*;
+
+use crate::ctrs::scan::ScanImage;
+
+// Median of the values in a window of `radius` samples on either side of each sample.
+// The window is cut off at the edges.
+fn median_filter(values: &[f32], radius: usize) -> Vec<f32> {
+    let mut window = Vec::with_capacity(2*radius + 1);
+
+    (0..values.len())
+        .map(|i| {
+            window.clear();
+            window.extend_from_slice(&values[i.saturating_sub(radius)..(i + radius + 1).min(values.len())]);
+
+            let middle = window.len() / 2;
+            *window.select_nth_unstable_by(middle, f32::total_cmp).1
+        })
+        .collect()
+}
+
+// Remove stripes from a sinogram (projections × detector columns) with the sorting based method of
+// Vo et al. ("Superior techniques for eliminating ring artifacts in X-ray micro-tomography", 2018).
+// Each column is sorted along the projections, which turns the features of the object into smooth
+// gradients while a column that responds differently from its neighbours stays a stripe. The sorted
+// sinogram is smoothed across columns to remove the stripes, and each smoothed value is put back at
+// the projection it was sorted from.
+fn remove_stripes(sinogram: &mut [f32], width: usize, radius: usize) {
+    let n_projections = sinogram.len() / width;
+
+    // the projection that each rank of each column was sorted from, stored with the ranks varying fastest
+    let order: Vec<usize> = (0..width)
+        .flat_map(|x| {
+            let mut indices: Vec<usize> = (0..n_projections).collect();
+            indices.sort_by(|a, b| sinogram[a*width + x].total_cmp(&sinogram[b*width + x]));
+            indices
+        })
+        .collect();
+
+    // smooth each rank of the sorted sinogram across the columns and un-sort the result
+    let mut corrected = vec![0.; sinogram.len()];
+    for rank in 0..n_projections {
+        let row: Vec<f32> = (0..width).map(|x| sinogram[order[x*n_projections + rank]*width + x]).collect();
+        let smoothed = median_filter(&row, radius);
+
+        for (x, value) in smoothed.into_iter().enumerate() {
+            corrected[order[x*n_projections + rank]*width + x] = value;
+        }
+    }
+
+    sinogram.copy_from_slice(&corrected);
+}
+
+// Suppress ring artifacts by removing stripes from the sinogram of each detector row. The
+// strength is the number of columns on either side of each column it is smoothed with.
+// The stripes are removed from the line integrals, as they are additive there.
+pub fn remove_rings(images: &mut [ScanImage], strength: u32) {
+    if images.is_empty() || strength == 0 {
+        return;
+    }
+
+    let (width, height) = images[0].dimensions();
+    let row_len = width as usize;
+
+    // the rows of all projections grouped by detector row, so each sinogram can be corrected in place
+    let mut rows: Vec<Vec<&mut [f32]>> = (0..height).map(|_| Vec::with_capacity(images.len())).collect();
+    for image in images.iter_mut() {
+        for (y, row) in image.chunks_exact_mut(row_len).enumerate() {
+            rows[y].push(row);
+        }
+    }
+
+    rows.into_par_iter().for_each(|mut rows| {
+        let mut sinogram: Vec<f32> = rows.iter()
+            .flat_map(|row| row.iter())
+            .map(|sample| -sample.ln())
+            .collect();
+
+        remove_stripes(&mut sinogram, row_len, strength as usize);
+
+        for (row, line_integrals) in rows.iter_mut().zip(sinogram.chunks_exact(row_len)) {
+            for (sample, line_integral) in row.iter_mut().zip(line_integrals) {
+                *sample = (-line_integral).exp();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_column_offsets() {
+        let (width, n_projections) = (64, 90);
+
+        // the sinogram of an off-center disk, with a different offset in two columns
+        let clean: Vec<f32> = (0..n_projections)
+            .flat_map(|i| {
+                let center = 32. + 12. * (i as f32 * 0.07).sin();
+                (0..width).map(move |x| (1. - ((x as f32 - center) / 8.).powi(2)).max(0.).sqrt())
+            })
+            .collect();
+        let mut striped = clean.clone();
+        for i in 0..n_projections {
+            striped[i*width + 20] += 0.3;
+            striped[i*width + 41] -= 0.2;
+        }
+
+        let error = |sinogram: &[f32]| sinogram.iter().zip(&clean).map(|(a, b)| (a - b).powi(2)).sum::<f32>();
+        let before = error(&striped);
+        remove_stripes(&mut striped, width, 2);
+        let offset = |x: usize| (0..n_projections).map(|i| striped[i*width + x] - clean[i*width + x]).sum::<f32>() / n_projections as f32;
+
+        // the smoothing blurs the edges of the disk a little, but the stripes are mostly gone
+        assert!(offset(20).abs() < 0.15 * 0.3, "{}", offset(20));
+        assert!(offset(41).abs() < 0.15 * 0.2, "{}", offset(41));
+        assert!(error(&striped) < 0.5 * before, "{} vs {before}", error(&striped));
+    }
+}
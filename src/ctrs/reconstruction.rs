@@ -0,0 +1,256 @@
+pub mod algebraic;
+pub mod backprojection;
+pub mod forward_projection;
+pub mod metal;
+pub mod regularized;
+pub mod statistical;
+
+use std::fmt::{Debug, Display};
+
+use super::{preprocess::{self, ProcessingSettings}, scan::CtScan, scene};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReconstructionMode {
+    // filtered back-projection without any cone-beam weighting
+    Fbp,
+    // Feldkamp-Davis-Kress: cosine pre-weighting of the projections and
+    // 1/U² distance weighting of the back-projected values
+    #[default]
+    Fdk,
+    // Simultaneous Iterative Reconstruction Technique: updates the volume
+    // with the residuals of all projections at once in each iteration
+    Sirt,
+    // Simultaneous Algebraic Reconstruction Technique: updates the volume
+    // with the residual of one projection at a time
+    Sart,
+    // Ordered Subsets Expectation Maximization: maximizes the Poisson likelihood
+    // of the measured intensities, updating the volume with one subset of the
+    // projections at a time. With a single subset this is MLEM.
+    OsEm,
+    // total variation regularized reconstruction with ASD-POCS, for scans with few projections
+    Tv,
+    // FDK with metal artifact reduction: the projections of high density inclusions are
+    // inpainted before reconstructing again, and the inclusions are put back afterwards
+    Mar,
+}
+
+impl ReconstructionMode {
+    pub const ALL: [ReconstructionMode; 7] = [
+        ReconstructionMode::Fbp,
+        ReconstructionMode::Fdk,
+        ReconstructionMode::Sirt,
+        ReconstructionMode::Sart,
+        ReconstructionMode::OsEm,
+        ReconstructionMode::Tv,
+        ReconstructionMode::Mar,
+    ];
+
+    // iterative modes reconstruct the volume from the unfiltered projections over several iterations
+    pub fn is_iterative(&self) -> bool {
+        match self {
+            ReconstructionMode::Fbp | ReconstructionMode::Fdk | ReconstructionMode::Mar => false,
+            ReconstructionMode::Sirt
+            | ReconstructionMode::Sart
+            | ReconstructionMode::OsEm
+            | ReconstructionMode::Tv => true,
+        }
+    }
+
+    // these modes are reconstructed on the CPU, the rest are back-projected on the GPU
+    pub fn on_cpu(&self) -> bool {
+        self.is_iterative() || *self == ReconstructionMode::Mar
+    }
+
+    // modes that apply the FDK cone-beam weights
+    pub fn is_cone_beam_weighted(&self) -> bool {
+        matches!(self, ReconstructionMode::Fdk | ReconstructionMode::Mar)
+    }
+}
+
+impl Display for ReconstructionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReconstructionMode::Fbp => "FBP",
+            ReconstructionMode::Fdk => "FDK",
+            ReconstructionMode::Sirt => "SIRT",
+            ReconstructionMode::Sart => "SART",
+            ReconstructionMode::OsEm => "OS-EM",
+            ReconstructionMode::Tv => "TV",
+            ReconstructionMode::Mar => "MAR",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterativeSettings {
+    pub iterations: u32,
+    // factor that each update to the volume is scaled by
+    pub relaxation: f32,
+    // clamp negative voxels to zero after each update
+    pub non_negative: bool,
+    // number of subsets the projections are split into for OS-EM
+    pub subsets: u32,
+    // strength of the total variation regularization, relative to the data updates
+    pub tv_weight: f32,
+}
+
+impl Default for IterativeSettings {
+    fn default() -> Self {
+        Self {
+            iterations: 10,
+            relaxation: 1.,
+            non_negative: true,
+            subsets: 8,
+            tv_weight: 0.5,
+        }
+    }
+}
+
+// Reconstruct a scan on the CPU with any of the reconstruction modes. `progress` is called
+// with the volume after each iteration (once for the analytic modes), and the
+// reconstruction is stopped early if it returns false.
+pub fn reconstruct(
+    scan: &CtScan,
+    settings: &ProcessingSettings,
+    resolution: u32,
+    mut progress: impl FnMut(u32, &Volume) -> bool,
+) -> Volume {
+    let dimensions = (resolution, resolution, resolution);
+    let projections = scene::projection_geometry(scan);
+
+    if settings.mode == ReconstructionMode::Mar {
+        let volume = metal::reduce_metal_artifacts(scan, settings, &projections, dimensions);
+        progress(1, &volume);
+
+        return volume;
+    }
+
+    // the statistical mode models the measured intensities rather than the line integrals
+    let projection_data = match settings.mode {
+        ReconstructionMode::OsEm => preprocess::transmission(scan, settings),
+        _ => preprocess::preprocess(scan, settings),
+    };
+
+    if !settings.mode.is_iterative() {
+        let volume = backprojection::backproject(
+            &projection_data,
+            &projections,
+            dimensions,
+            scene::VOLUME_SIZE,
+            settings.mode.is_cone_beam_weighted(),
+        );
+        progress(1, &volume);
+
+        return volume;
+    }
+
+    // Every iteration forward projects the whole volume, so the projections are binned
+    // such that detector pixels are roughly the size of the voxels.
+    let bin_factor = preprocess::bin_factor(projection_data[0].dimensions(), resolution);
+    let projection_data = preprocess::bin(&projection_data, bin_factor);
+
+    let volume = Volume::new(dimensions, scene::VOLUME_SIZE);
+    match settings.mode {
+        ReconstructionMode::Sirt => algebraic::sirt(volume, &projection_data, &projections, &settings.iterative, progress),
+        ReconstructionMode::Sart => algebraic::sart(volume, &projection_data, &projections, &settings.iterative, progress),
+        ReconstructionMode::OsEm => statistical::os_em(volume, &projection_data, &projections, &settings.iterative, progress),
+        ReconstructionMode::Tv => regularized::asd_pocs(volume, &projection_data, &projections, &settings.iterative, progress),
+        ReconstructionMode::Fbp | ReconstructionMode::Fdk | ReconstructionMode::Mar => unreachable!(),
+    }
+}
+
+// A reconstructed volume in memory. Voxels are stored with x varying fastest,
+// then y, then z, which is the same layout as the volume texture on the GPU.
+#[derive(Clone)]
+pub struct Volume {
+    pub dimensions: (u32, u32, u32),
+    // edge length of the volume in world units, the volume is centered on the world origin
+    pub size: f32,
+    pub data: Vec<f32>,
+}
+
+// don't print the voxels, there are a _lot_ of them
+impl Debug for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Volume")
+            .field("dimensions", &self.dimensions)
+            .field("size", &self.size)
+            .field("data", &"...")
+            .finish()
+    }
+}
+
+impl Volume {
+    pub fn new(dimensions: (u32, u32, u32), size: f32) -> Self {
+        let len = dimensions.0 as usize * dimensions.1 as usize * dimensions.2 as usize;
+
+        Self {
+            dimensions,
+            size,
+            data: vec![0.; len],
+        }
+    }
+
+    // number of voxels in a single z-slice
+    pub fn slice_len(&self) -> usize {
+        self.dimensions.0 as usize * self.dimensions.1 as usize
+    }
+
+    pub fn voxel_size(&self) -> f32 {
+        self.size / self.dimensions.0.max(self.dimensions.1).max(self.dimensions.2) as f32
+    }
+
+    pub fn get(&self, voxel: (u32, u32, u32)) -> f32 {
+        self.data[voxel.0 as usize + voxel.1 as usize * self.dimensions.0 as usize + voxel.2 as usize * self.slice_len()]
+    }
+
+    // Trilinearly interpolated value at a point in world coordinates.
+    // The volume is zero outside of its bounds.
+    pub fn sample(&self, point_world: [f32; 3]) -> f32 {
+        let dimensions = [self.dimensions.0, self.dimensions.1, self.dimensions.2];
+
+        // continuous voxel coordinates, where voxel centers are at whole numbers
+        let coords: [f32; 3] = std::array::from_fn(|axis| {
+            (point_world[axis] / self.size + 0.5) * dimensions[axis] as f32 - 0.5
+        });
+
+        let base = coords.map(|coord| coord.floor());
+        let fract: [f32; 3] = std::array::from_fn(|axis| coords[axis] - base[axis]);
+
+        let mut value = 0.;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+
+            let mut weight = 1.;
+            let mut voxel = [0; 3];
+            for axis in 0..3 {
+                let index = base[axis] as i64 + offset[axis] as i64;
+                if index < 0 || index >= dimensions[axis] as i64 {
+                    weight = 0.;
+                    break;
+                }
+
+                voxel[axis] = index as u32;
+                weight *= if offset[axis] == 1 { fract[axis] } else { 1. - fract[axis] };
+            }
+
+            if weight > 0. {
+                value += weight * self.get((voxel[0], voxel[1], voxel[2]));
+            }
+        }
+
+        value
+    }
+
+    // position of the center of a voxel in world coordinates,
+    // CPU version of voxel_to_world in reconstruct.wgsl
+    pub fn voxel_to_world(&self, voxel: (u32, u32, u32)) -> [f32; 3] {
+        let to_world = |index: u32, n: u32| ((index as f32 + 0.5) / n as f32 - 0.5) * self.size;
+
+        [
+            to_world(voxel.0, self.dimensions.0),
+            to_world(voxel.1, self.dimensions.1),
+            to_world(voxel.2, self.dimensions.2),
+        ]
+    }
+}
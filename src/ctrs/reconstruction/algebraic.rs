@@ -0,0 +1,132 @@
+use image::ImageBuffer;
+use rayon::prelude::*;
+
+use crate::ctrs::{scan::ScanImage, scene::pipeline::uniforms::Projection};
+
+use super::{backprojection, forward_projection::{forward_project_like, ray_lengths}, IterativeSettings, Volume};
+
+// rays shorter than this (in world units) are considered to miss the volume
+const MIN_RAY_LENGTH: f32 = 1e-3;
+
+// The measured projection minus the forward projection of the current volume,
+// divided by the length of each ray through the volume.
+fn normalized_residual(measured: &ScanImage, projected: &ScanImage, ray_lengths: &ScanImage) -> ScanImage {
+    let data = measured.iter().zip(projected.iter()).zip(ray_lengths.iter())
+        .map(|((measured, projected), length)| {
+            if *length > MIN_RAY_LENGTH {
+                (measured - projected) / length
+            } else {
+                0.
+            }
+        })
+        .collect();
+
+    ImageBuffer::from_raw(measured.width(), measured.height(), data).unwrap()
+}
+
+fn apply_update(volume: &mut Volume, update: &Volume, weights: Option<&Volume>, settings: &IterativeSettings) {
+    volume.data.par_iter_mut().enumerate().for_each(|(i, voxel)| {
+        let weight = match weights {
+            Some(weights) if weights.data[i] > 0. => 1. / weights.data[i],
+            Some(_) => 0.,
+            None => 1.,
+        };
+
+        *voxel += settings.relaxation * weight * update.data[i];
+
+        if settings.non_negative {
+            *voxel = voxel.max(0.);
+        }
+    });
+}
+
+// SIRT: each iteration back-projects the residuals of all projections and
+// normalizes the update by the number of rays through each voxel.
+pub fn sirt(
+    mut volume: Volume,
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    settings: &IterativeSettings,
+    mut progress: impl FnMut(u32, &Volume) -> bool,
+) -> Volume {
+    let ray_lengths = ray_lengths(&volume, projection_data, projections);
+
+    // the column sums of the system matrix
+    let ones: Vec<ScanImage> = projection_data.iter()
+        .map(|image| ImageBuffer::from_pixel(image.width(), image.height(), [1.].into()))
+        .collect();
+    let column_sums = backprojection::backproject_sum(&ones, projections, volume.dimensions, volume.size);
+
+    for iteration in 1..=settings.iterations {
+        let residuals: Vec<ScanImage> = projection_data.iter().zip(projections).zip(&ray_lengths)
+            .map(|((measured, projection), lengths)| {
+                normalized_residual(measured, &forward_project_like(&volume, projection, measured), lengths)
+            })
+            .collect();
+
+        let update = backprojection::backproject_sum(&residuals, projections, volume.dimensions, volume.size);
+        apply_update(&mut volume, &update, Some(&column_sums), settings);
+
+        if !progress(iteration, &volume) {
+            break;
+        }
+    }
+
+    volume
+}
+
+// Order to visit the projections in for SART, where consecutive projections are
+// far apart in angle, which makes the reconstruction converge faster (golden ratio ordering).
+pub fn sart_order(n_projections: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..n_projections).collect();
+    order.sort_by(|a, b| {
+        (*a as f32 * 0.618_034).fract().total_cmp(&(*b as f32 * 0.618_034).fract())
+    });
+
+    order
+}
+
+// A single SART iteration, updating the volume with the residual of each projection in turn
+pub fn sart_sweep(
+    volume: &mut Volume,
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    ray_lengths: &[ScanImage],
+    order: &[usize],
+    settings: &IterativeSettings,
+) {
+    for &i in order {
+        let measured = &projection_data[i];
+        let residual = normalized_residual(
+            measured,
+            &forward_project_like(volume, &projections[i], measured),
+            &ray_lengths[i],
+        );
+
+        // the bilinear weights of each voxel sum to one, so the update needs no further normalization
+        let update = backprojection::backproject_sum(&[residual], &projections[i..=i], volume.dimensions, volume.size);
+        apply_update(volume, &update, None, settings);
+    }
+}
+
+// SART: the volume is updated with the residual of a single projection at a time.
+pub fn sart(
+    mut volume: Volume,
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    settings: &IterativeSettings,
+    mut progress: impl FnMut(u32, &Volume) -> bool,
+) -> Volume {
+    let ray_lengths = ray_lengths(&volume, projection_data, projections);
+    let order = sart_order(projections.len());
+
+    for iteration in 1..=settings.iterations {
+        sart_sweep(&mut volume, projection_data, projections, &ray_lengths, &order, settings);
+
+        if !progress(iteration, &volume) {
+            break;
+        }
+    }
+
+    volume
+}
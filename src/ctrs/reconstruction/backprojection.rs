@@ -0,0 +1,161 @@
+use rayon::prelude::*;
+
+use crate::ctrs::{scan::ScanImage, scene::pipeline::uniforms::Projection};
+
+use super::Volume;
+
+// Sample an image like the nearest-neighbour, clamp-to-edge sampler used for
+// the projections texture on the GPU. `point_texture` is in texture coordinates.
+pub fn sample_nearest(image: &ScanImage, point_texture: [f32; 2]) -> f32 {
+    let (width, height) = image.dimensions();
+
+    let x = ((point_texture[0] * width as f32).floor().max(0.) as u32).min(width - 1);
+    let y = ((point_texture[1] * height as f32).floor().max(0.) as u32).min(height - 1);
+
+    image.get_pixel(x, y).0[0]
+}
+
+// CPU version of sample_volume in reconstruct.wgsl, the two must be kept in sync
+pub fn sample_volume(
+    point_world: [f32; 3],
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    distance_weighting: bool,
+) -> f32 {
+    let mut sample_value = 0.;
+    let mut hits = 0;
+    for (image, projection) in projection_data.iter().zip(projections) {
+        let point_proj = projection.project_point(point_world);
+        if point_proj[2] > 0. {
+            let point_texture = projection.projection_to_texture([point_proj[0], point_proj[1]]);
+
+            // the detector covers 0..1 in texture coordinates
+            if point_texture.iter().all(|coord| (0. ..=1.).contains(coord)) {
+                // U is the distance from the source to the point along the central
+                // ray, relative to the source-object-distance
+                let weight = if distance_weighting {
+                    let u = (projection.source[1] - point_proj[2]) / projection.sod;
+                    1. / (u*u)
+                } else {
+                    1.
+                };
+
+                sample_value += weight * sample_nearest(image, point_texture);
+                hits += 1;
+            }
+        }
+    }
+
+    if hits == projections.len() {
+        return sample_value / projections.len() as f32;
+    }
+
+    0.
+}
+
+// Back-project the (preprocessed) projections into a new volume on the CPU.
+// This does the same as the back-projection compute shader, so the results of the two can be compared.
+pub fn backproject(
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    dimensions: (u32, u32, u32),
+    size: f32,
+    distance_weighting: bool,
+) -> Volume {
+    let mut volume = Volume::new(dimensions, size);
+
+    // take the voxels out of the volume so it can still be used for its geometry
+    let mut data = std::mem::take(&mut volume.data);
+
+    // each z-slice is reconstructed as a separate job
+    data.par_chunks_mut(volume.slice_len()).enumerate().for_each(|(z, slice)| {
+        for (i, voxel) in slice.iter_mut().enumerate() {
+            let x = i as u32 % dimensions.0;
+            let y = i as u32 / dimensions.0;
+
+            let point_world = volume.voxel_to_world((x, y, z as u32));
+            *voxel = sample_volume(point_world, projection_data, projections, distance_weighting);
+        }
+    });
+
+    volume.data = data;
+    volume
+}
+
+// Bilinearly interpolated value of an image at a point in texture coordinates.
+// The image is zero outside of its bounds.
+pub fn sample_bilinear(image: &ScanImage, point_texture: [f32; 2]) -> f32 {
+    let (width, height) = image.dimensions();
+
+    // continuous pixel coordinates, where pixel centers are at whole numbers
+    let x = point_texture[0] * width as f32 - 0.5;
+    let y = point_texture[1] * height as f32 - 0.5;
+
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let pixel = |px: f32, py: f32| {
+        if px < 0. || py < 0. || px >= width as f32 || py >= height as f32 {
+            0.
+        } else {
+            image.get_pixel(px as u32, py as u32).0[0]
+        }
+    };
+
+    (1.-fx)*(1.-fy)*pixel(x0, y0) + fx*(1.-fy)*pixel(x0+1., y0)
+        + (1.-fx)*fy*pixel(x0, y0+1.) + fx*fy*pixel(x0+1., y0+1.)
+}
+
+// Sum the bilinearly interpolated values that each voxel projects onto in the given images.
+// Unlike `backproject` nothing is weighted or averaged, so this is (approximately) the
+// transpose of the forward projection, as needed by the iterative modes.
+pub fn backproject_sum(
+    images: &[ScanImage],
+    projections: &[Projection],
+    dimensions: (u32, u32, u32),
+    size: f32,
+) -> Volume {
+    let mut volume = Volume::new(dimensions, size);
+    let mut data = std::mem::take(&mut volume.data);
+
+    data.par_chunks_mut(volume.slice_len()).enumerate().for_each(|(z, slice)| {
+        for (i, voxel) in slice.iter_mut().enumerate() {
+            let x = i as u32 % dimensions.0;
+            let y = i as u32 / dimensions.0;
+
+            let point_world = volume.voxel_to_world((x, y, z as u32));
+            *voxel = images.iter().zip(projections)
+                .map(|(image, projection)| {
+                    let point_proj = projection.project_point(point_world);
+                    sample_bilinear(image, projection.projection_to_texture([point_proj[0], point_proj[1]]))
+                })
+                .sum();
+        }
+    });
+
+    volume.data = data;
+    volume
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctrs::scene::pipeline::uniforms::Misalignment;
+
+    use super::*;
+
+    #[test]
+    fn points_outside_the_field_of_view_are_not_reconstructed() {
+        // a 20×10 mm detector, magnifying the center of rotation 1.25 times
+        let projections: Vec<Projection> = (0..8)
+            .map(|i| Projection::new(i as f32 * std::f32::consts::PI / 4., 200., 250., (20., 10.), &Misalignment::default()))
+            .collect();
+        let images = vec![ScanImage::from_pixel(20, 10, image::Luma([1.])); projections.len()];
+
+        assert_eq!(sample_volume([0., 0., 0.], &images, &projections, false), 1.);
+        assert_eq!(sample_volume([0., 0., 3.], &images, &projections, false), 1.);
+
+        // beyond the top of the detector, and outside the cone on every side
+        assert_eq!(sample_volume([0., 0., 5.], &images, &projections, false), 0.);
+        assert_eq!(sample_volume([15., 15., 0.], &images, &projections, false), 0.);
+    }
+}
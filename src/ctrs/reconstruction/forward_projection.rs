@@ -0,0 +1,90 @@
+use image::ImageBuffer;
+use rayon::prelude::*;
+
+use crate::ctrs::{scan::ScanImage, scene::pipeline::uniforms::Projection};
+
+use super::Volume;
+
+// Distances along a ray at which it enters and leaves the volume,
+// or None if it misses the volume entirely.
+fn clip_ray(volume: &Volume, origin: [f32; 3], direction: [f32; 3]) -> Option<(f32, f32)> {
+    let half_size = volume.size / 2.;
+
+    let mut near = f32::MIN;
+    let mut far = f32::MAX;
+    for axis in 0..3 {
+        if direction[axis] == 0. {
+            if origin[axis].abs() > half_size {
+                return None;
+            }
+            continue;
+        }
+
+        let t0 = (-half_size - origin[axis]) / direction[axis];
+        let t1 = (half_size - origin[axis]) / direction[axis];
+
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+    }
+
+    (near < far && far > 0.).then_some((near.max(0.), far))
+}
+
+// Integrate the volume along the straight line from `from` to `to`
+fn ray_integral(volume: &Volume, from: [f32; 3], to: [f32; 3]) -> f32 {
+    let delta: [f32; 3] = std::array::from_fn(|axis| to[axis] - from[axis]);
+    let length = delta.iter().map(|d| d*d).sum::<f32>().sqrt();
+    let direction = delta.map(|d| d / length);
+
+    let Some((near, far)) = clip_ray(volume, from, direction) else {
+        return 0.;
+    };
+
+    // sample at the midpoints of steps of at most half a voxel
+    let n_steps = ((far - near) / (volume.voxel_size() / 2.)).ceil().max(1.);
+    let step = (far - near) / n_steps;
+
+    (0..n_steps as u32)
+        .map(|i| {
+            let t = near + (i as f32 + 0.5) * step;
+            volume.sample(std::array::from_fn(|axis| from[axis] + t*direction[axis]))
+        })
+        .sum::<f32>() * step
+}
+
+// Ray-driven forward projection: integrate the volume along the ray from the source to
+// the center of every pixel of a detector with the given dimensions. This uses the same
+// projection geometry as the back-projection, and it is the forward model of the iterative modes.
+pub fn forward_project(volume: &Volume, projection: &Projection, width: u32, height: u32) -> ScanImage {
+    let source = projection.source_position();
+
+    let data = (0..width*height).into_par_iter()
+        .map(|i| {
+            let point_texture = [
+                ((i % width) as f32 + 0.5) / width as f32,
+                ((i / width) as f32 + 0.5) / height as f32,
+            ];
+            let detector = projection.detector_to_world(projection.texture_to_projection(point_texture));
+
+            ray_integral(volume, source, detector)
+        })
+        .collect();
+
+    ImageBuffer::from_raw(width, height, data).unwrap()
+}
+
+// forward project onto a detector with the same dimensions as `image`
+pub fn forward_project_like(volume: &Volume, projection: &Projection, image: &ScanImage) -> ScanImage {
+    forward_project(volume, projection, image.width(), image.height())
+}
+
+// Length of the ray to each detector pixel through the volume, which are the row sums of the
+// system matrix. `volume` is only used for its geometry.
+pub fn ray_lengths(volume: &Volume, projection_data: &[ScanImage], projections: &[Projection]) -> Vec<ScanImage> {
+    let mut ones = Volume::new(volume.dimensions, volume.size);
+    ones.data.fill(1.);
+
+    projection_data.iter().zip(projections)
+        .map(|(image, projection)| forward_project_like(&ones, projection, image))
+        .collect()
+}
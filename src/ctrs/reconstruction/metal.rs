@@ -0,0 +1,162 @@
+use rayon::prelude::*;
+
+use crate::ctrs::{
+    preprocess::{self, ProcessingSettings},
+    scan::{CtScan, ScanImage},
+    scene::{pipeline::uniforms::Projection, VOLUME_SIZE},
+};
+
+use super::{backprojection, Volume};
+
+// extra pixels around the projected inclusions that are inpainted as well, to cover the blur at their edges
+const MASK_MARGIN: f32 = 1.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetalSettings {
+    // voxels above this fraction of the brightest voxel in the first reconstruction are metal
+    pub threshold: f32,
+}
+
+impl Default for MetalSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+        }
+    }
+}
+
+// Weight, filter and scale the line integrals, and back-project them like FDK
+fn reconstruct_fdk(
+    mut images: Vec<ScanImage>,
+    scan: &CtScan,
+    settings: &ProcessingSettings,
+    projections: &[Projection],
+    dimensions: (u32, u32, u32),
+    max: Option<f32>,
+) -> (Volume, f32) {
+    preprocess::weight_and_filter(&mut images, scan, settings);
+
+    let max = max.unwrap_or_else(|| preprocess::max_sample(&images));
+    preprocess::scale(&mut images, 1. / max);
+
+    (backprojection::backproject(&images, projections, dimensions, VOLUME_SIZE, true), max)
+}
+
+// Pixels of a projection that the given voxels project onto. Each voxel covers a square
+// around its projected center, as large as the voxel appears from the source.
+fn metal_mask(volume: &Volume, voxels: &[(u32, u32, u32)], projection: &Projection, dimensions: (u32, u32)) -> Vec<bool> {
+    let (width, height) = dimensions;
+    let mut mask = vec![false; (width*height) as usize];
+
+    // pixels per unit along the detector, from the columns of the (column-major) texture transform
+    let [[a, c], [b, d], _] = projection.texture_transform;
+    let scale_x = a.hypot(c) * width as f32;
+    let scale_y = b.hypot(d) * height as f32;
+
+    // half the diagonal of a voxel
+    let radius = 0.87 * volume.voxel_size();
+
+    for voxel in voxels {
+        let point_proj = projection.project_point(volume.voxel_to_world(*voxel));
+        if point_proj[2] <= 0. {
+            continue;
+        }
+
+        let point_texture = projection.projection_to_texture([point_proj[0], point_proj[1]]);
+        let magnification = projection.source[1] / (projection.source[1] - point_proj[2]);
+
+        // pixel coordinates, where pixel centers are at whole numbers
+        let (x, y) = (point_texture[0] * width as f32 - 0.5, point_texture[1] * height as f32 - 0.5);
+        let (radius_x, radius_y) = (radius * magnification * scale_x + MASK_MARGIN, radius * magnification * scale_y + MASK_MARGIN);
+
+        let xs = (x - radius_x).ceil().max(0.) as u32..((x + radius_x).floor() + 1.).clamp(0., width as f32) as u32;
+        let ys = (y - radius_y).ceil().max(0.) as u32..((y + radius_y).floor() + 1.).clamp(0., height as f32) as u32;
+        for py in ys {
+            for px in xs.clone() {
+                mask[(py*width + px) as usize] = true;
+            }
+        }
+    }
+
+    mask
+}
+
+// Replace the masked pixels in each row by linearly interpolating between the unmasked pixels on
+// either side. Runs that touch the edge of the image are filled with the value next to them.
+fn inpaint(image: &mut ScanImage, mask: &[bool]) {
+    let width = image.width() as usize;
+
+    for (row, row_mask) in image.chunks_exact_mut(width).zip(mask.chunks_exact(width)) {
+        let mut x = 0;
+        while x < width {
+            if !row_mask[x] {
+                x += 1;
+                continue;
+            }
+
+            let start = x;
+            while x < width && row_mask[x] {
+                x += 1;
+            }
+
+            let left = start.checked_sub(1).map(|left| row[left]);
+            let right = (x < width).then(|| row[x]);
+            let (left, right) = match (left, right) {
+                (Some(left), Some(right)) => (left, right),
+                (Some(value), None) | (None, Some(value)) => (value, value),
+                // the whole row is masked, there is nothing to interpolate from
+                (None, None) => continue,
+            };
+
+            let len = (x - start + 1) as f32;
+            for (i, sample) in row[start..x].iter_mut().enumerate() {
+                let t = (i + 1) as f32 / len;
+                *sample = left + t * (right - left);
+            }
+        }
+    }
+}
+
+// Reconstruct a scan with metal artifact reduction. High density inclusions are segmented from a
+// first FDK reconstruction and forward projected, to find the detector pixels that see them. Those
+// pixels are inpainted from their neighbours in the line integrals, so the inclusions no longer cause
+// streaks, and the scan is reconstructed again. Finally the inclusions are put back into the volume.
+pub fn reduce_metal_artifacts(
+    scan: &CtScan,
+    settings: &ProcessingSettings,
+    projections: &[Projection],
+    dimensions: (u32, u32, u32),
+) -> Volume {
+    let mut line_integrals = preprocess::line_integrals(scan, settings);
+    let (first, max) = reconstruct_fdk(line_integrals.clone(), scan, settings, projections, dimensions, None);
+
+    let brightest = first.data.iter().copied().fold(0., f32::max);
+    let metal: Vec<usize> = (0..first.data.len())
+        .filter(|i| first.data[*i] > settings.metal.threshold * brightest)
+        .collect();
+
+    if brightest <= 0. || metal.is_empty() {
+        log::info!("No metal found in {}", scan.name);
+        return first;
+    }
+
+    log::info!("Inpainting the projections of {} metal voxels", metal.len());
+
+    let (width, slice_len) = (dimensions.0 as usize, first.slice_len());
+    let voxels: Vec<(u32, u32, u32)> = metal.iter()
+        .map(|i| ((i % width) as u32, (i % slice_len / width) as u32, (i / slice_len) as u32))
+        .collect();
+
+    line_integrals.par_iter_mut().zip(projections).for_each(|(image, projection)| {
+        let mask = metal_mask(&first, &voxels, projection, image.dimensions());
+        inpaint(image, &mask);
+    });
+
+    // scale the second pass like the first, so the metal voxels fit in
+    let (mut volume, _) = reconstruct_fdk(line_integrals, scan, settings, projections, dimensions, Some(max));
+    for i in metal {
+        volume.data[i] = first.data[i];
+    }
+
+    volume
+}
@@ -0,0 +1,124 @@
+use rayon::prelude::*;
+
+use crate::ctrs::{scan::ScanImage, scene::pipeline::uniforms::Projection};
+
+use super::{algebraic, forward_projection::ray_lengths, IterativeSettings, Volume};
+
+// number of TV gradient descent steps after each SART iteration
+const TV_STEPS: u32 = 20;
+// factor the relaxation is reduced by after each iteration
+const RELAXATION_REDUCTION: f32 = 0.995;
+// factor the TV step size is reduced by when it changes the volume more than the data step
+const TV_STEP_REDUCTION: f32 = 0.95;
+// max ratio between the change from the TV steps and the change from the data step
+const MAX_CHANGE_RATIO: f32 = 0.95;
+// avoids division by zero in flat regions when computing the TV gradient
+const TV_EPSILON: f32 = 1e-8;
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.par_iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum::<f32>().sqrt()
+}
+
+// Gradient of the (isotropic, smoothed) total variation of the volume with respect to each voxel,
+// using backward differences. Differences across the edge of the volume are zero.
+fn tv_gradient(volume: &Volume) -> Vec<f32> {
+    let (width, height, depth) = volume.dimensions;
+    let strides = [1, width as usize, volume.slice_len()];
+    let dimensions = [width, height, depth];
+
+    let coords = |i: usize| [
+        (i % width as usize) as u32,
+        (i / width as usize % height as usize) as u32,
+        (i / volume.slice_len()) as u32,
+    ];
+
+    // the gradient magnitude at each voxel
+    let magnitudes: Vec<f32> = (0..volume.data.len()).into_par_iter()
+        .map(|i| {
+            let voxel = coords(i);
+            let sum: f32 = (0..3)
+                .filter(|&axis| voxel[axis] > 0)
+                .map(|axis| (volume.data[i] - volume.data[i - strides[axis]]).powi(2))
+                .sum();
+
+            (TV_EPSILON + sum).sqrt()
+        })
+        .collect();
+
+    (0..volume.data.len()).into_par_iter()
+        .map(|i| {
+            let voxel = coords(i);
+
+            (0..3)
+                .map(|axis| {
+                    let mut gradient = 0.;
+                    if voxel[axis] > 0 {
+                        gradient += (volume.data[i] - volume.data[i - strides[axis]]) / magnitudes[i];
+                    }
+                    if voxel[axis] + 1 < dimensions[axis] {
+                        let next = i + strides[axis];
+                        gradient -= (volume.data[next] - volume.data[i]) / magnitudes[next];
+                    }
+
+                    gradient
+                })
+                .sum()
+        })
+        .collect()
+}
+
+// ASD-POCS (Sidky and Pan, "Image reconstruction in circular cone-beam computed tomography by
+// constrained, total-variation minimization", 2008). Each iteration alternates a SART step
+// towards consistency with the data and non-negativity, with steepest descent steps that
+// reduce the total variation of the volume. The TV step size is adapted such that the TV steps
+// don't undo the data step, starting at `tv_weight` times the change from the data step.
+pub fn asd_pocs(
+    mut volume: Volume,
+    projection_data: &[ScanImage],
+    projections: &[Projection],
+    settings: &IterativeSettings,
+    mut progress: impl FnMut(u32, &Volume) -> bool,
+) -> Volume {
+    let ray_lengths = ray_lengths(&volume, projection_data, projections);
+    let order = algebraic::sart_order(projections.len());
+
+    let mut data_settings = IterativeSettings {
+        non_negative: true,
+        ..*settings
+    };
+    let mut tv_scale = settings.tv_weight;
+
+    for iteration in 1..=settings.iterations {
+        let previous = volume.data.clone();
+
+        algebraic::sart_sweep(&mut volume, projection_data, projections, &ray_lengths, &order, &data_settings);
+        data_settings.relaxation *= RELAXATION_REDUCTION;
+
+        let data_change = distance(&volume.data, &previous);
+        let step = tv_scale * data_change / TV_STEPS as f32;
+
+        let after_data = volume.data.clone();
+        for _ in 0..TV_STEPS {
+            let gradient = tv_gradient(&volume);
+            let norm = gradient.par_iter().map(|g| g*g).sum::<f32>().sqrt();
+            if norm == 0. {
+                break;
+            }
+
+            volume.data.par_iter_mut().zip(&gradient).for_each(|(voxel, gradient)| {
+                *voxel -= step * gradient / norm;
+            });
+        }
+
+        let tv_change = distance(&volume.data, &after_data);
+        if tv_change > MAX_CHANGE_RATIO * data_change {
+            tv_scale *= TV_STEP_REDUCTION;
+        }
+
+        if !progress(iteration, &volume) {
+            break;
+        }
+    }
+
+    volume
+}
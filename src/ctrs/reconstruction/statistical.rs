@@ -0,0 +1,82 @@
+use image::ImageBuffer;
+use rayon::prelude::*;
+
+use crate::ctrs::{scan::ScanImage, scene::pipeline::uniforms::Projection};
+
+use super::{backprojection, forward_projection::{forward_project_like, ray_lengths}, IterativeSettings, Volume};
+
+// Initial estimate for OS-EM: a uniform volume with the mean attenuation along all rays
+// that pass through the volume. The updates are multiplicative, so it must be positive.
+fn initial_attenuation(intensities: &[ScanImage], ray_lengths: &[ScanImage]) -> f32 {
+    let (attenuation, length) = intensities.iter().zip(ray_lengths)
+        .flat_map(|(intensities, lengths)| intensities.iter().zip(lengths.iter()))
+        .filter(|(_, length)| **length > 0.)
+        .fold((0., 0.), |(attenuation, length), (intensity, ray_length)| {
+            (attenuation + (-intensity.max(f32::MIN_POSITIVE).ln()).max(0.), length + ray_length)
+        });
+
+    if length > 0. {
+        (attenuation / length).max(f32::EPSILON)
+    } else {
+        f32::EPSILON
+    }
+}
+
+// OS-EM for transmission data, using the convex algorithm by Lange and Fessler with ordered subsets
+// (Kamphuis and Beekman, "Accelerated iterative transmission CT reconstruction using an
+// ordered subsets convex algorithm", 1998). The intensities are assumed to be relative to the
+// unattenuated intensity, so the expected intensity of a ray is exp(-line integral).
+pub fn os_em(
+    mut volume: Volume,
+    intensities: &[ScanImage],
+    projections: &[Projection],
+    settings: &IterativeSettings,
+    mut progress: impl FnMut(u32, &Volume) -> bool,
+) -> Volume {
+    let ray_lengths = ray_lengths(&volume, intensities, projections);
+    volume.data.fill(initial_attenuation(intensities, &ray_lengths));
+
+    // interleaved subsets, such that each subset covers the whole angular range
+    let n_subsets = (settings.subsets as usize).clamp(1, projections.len());
+
+    for iteration in 1..=settings.iterations {
+        for subset in 0..n_subsets {
+            let indices: Vec<usize> = (subset..projections.len()).step_by(n_subsets).collect();
+
+            let mut gradients = Vec::with_capacity(indices.len());
+            let mut curvatures = Vec::with_capacity(indices.len());
+            for &i in &indices {
+                let line_integrals = forward_project_like(&volume, &projections[i], &intensities[i]);
+                let (width, height) = line_integrals.dimensions();
+
+                // expected intensity minus measured intensity, and the expected intensity
+                // weighted by the line integral
+                let (gradient, curvature): (Vec<f32>, Vec<f32>) = line_integrals.iter().zip(intensities[i].iter())
+                    .map(|(line_integral, measured)| {
+                        let expected = (-line_integral).exp();
+                        (expected - measured, line_integral * expected)
+                    })
+                    .unzip();
+
+                gradients.push(ImageBuffer::from_raw(width, height, gradient).unwrap());
+                curvatures.push(ImageBuffer::from_raw(width, height, curvature).unwrap());
+            }
+
+            let subset_projections: Vec<Projection> = indices.iter().map(|&i| projections[i]).collect();
+            let numerator = backprojection::backproject_sum(&gradients, &subset_projections, volume.dimensions, volume.size);
+            let denominator = backprojection::backproject_sum(&curvatures, &subset_projections, volume.dimensions, volume.size);
+
+            volume.data.par_iter_mut().enumerate().for_each(|(j, voxel)| {
+                if denominator.data[j] > 0. {
+                    *voxel = (*voxel + *voxel * numerator.data[j] / denominator.data[j]).max(0.);
+                }
+            });
+        }
+
+        if !progress(iteration, &volume) {
+            break;
+        }
+    }
+
+    volume
+}
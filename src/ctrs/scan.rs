@@ -1,13 +1,17 @@
-use std::{fmt::Debug, io::{self, Cursor}, path::{Path, PathBuf}};
+use std::{fmt::{Debug, Display}, io::{self, Cursor}, path::{Path, PathBuf}, sync::Arc};
 
-use futures::future;
-use image::{ImageBuffer, ImageReader, Luma};
-use serde::Deserialize;
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use image::{ImageBuffer, ImageError, ImageReader, Luma};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use tokio::task;
 
+use super::preprocess::{bad_pixels, beam_hardening::FitCache, denoise::DenoiseCache};
+
 pub type ScanImage = ImageBuffer<Luma<f32>, Vec<f32>>;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum RotationDirection {
     // Both directions are looking down from above
     CW,  // Clockwise
@@ -23,20 +27,297 @@ impl RotationDirection {
     }
 }
 
-#[derive(Deserialize, Clone)]
+// An entry in the list of projections of a scan descriptor, either just the file name
+// or an object with the file name and the angle (in degrees) the projection was taken at
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ProjectionFile {
+    Path(PathBuf),
+    WithAngle {
+        file: PathBuf,
+        angle: f32,
+    },
+}
+
+impl ProjectionFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            ProjectionFile::Path(path) => path,
+            ProjectionFile::WithAngle { file, .. } => file,
+        }
+    }
+
+    pub fn angle(&self) -> Option<f32> {
+        match self {
+            ProjectionFile::Path(_) => None,
+            ProjectionFile::WithAngle { angle, .. } => Some(*angle),
+        }
+    }
+}
+
+// The projections of a scan descriptor, either listed one by one or as a pattern of file names
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ProjectionList {
+    Files(Vec<ProjectionFile>),
+    // A printf-style pattern like `projection%04d.tif` gives the files with the numbers start, start + 1, ...
+    // A glob pattern with `*` and `?` gives the matching files in the projections directory sorted by name,
+    // skipping the first `start` of them and taking `count` or all the rest.
+    Pattern {
+        pattern: String,
+        #[serde(default)]
+        start: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u32>,
+    },
+}
+
+impl ProjectionList {
+    // The files of the projections, where glob patterns are matched against the files in `images_dir`
+    async fn files(&self, images_dir: &Path) -> Result<Vec<ProjectionFile>, LoadError> {
+        let (pattern, start, count) = match self {
+            ProjectionList::Files(files) => return Ok(files.clone()),
+            ProjectionList::Pattern { pattern, start, count } => (pattern, *start, *count),
+        };
+
+        let invalid = |reason: &str| LoadError::Pattern(pattern.clone(), reason.into());
+
+        if pattern.contains(['*', '?']) {
+            let mut entries = tokio::fs::read_dir(images_dir).await
+                .map_err(|err| LoadError::from_io(images_dir.to_owned(), err))?;
+
+            let pattern: Vec<char> = pattern.chars().collect();
+            let mut names = Vec::new();
+            while let Some(entry) = entries.next_entry().await.map_err(|err| LoadError::Read(images_dir.to_owned(), err))? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if glob_matches(&pattern, &name.chars().collect::<Vec<char>>()) {
+                    names.push(name);
+                }
+            }
+            names.sort();
+
+            let available = names.len().saturating_sub(start as usize);
+            if let Some(count) = count.filter(|count| *count as usize > available) {
+                return Err(invalid(&format!("{count} projections are needed, but only {available} files match after skipping {start}")));
+            }
+
+            return Ok(names.into_iter()
+                .skip(start as usize)
+                .take(count.map_or(usize::MAX, |count| count as usize))
+                .map(|name| ProjectionFile::Path(name.into()))
+                .collect());
+        }
+
+        let count = count.ok_or_else(|| invalid("the number of projections is missing, add a count"))?;
+        (start..start + count)
+            .map(|index| format_index(pattern, index).map(|name| ProjectionFile::Path(name.into())))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid("it needs a placeholder like %d or %04d, or * and ? to match files"))
+    }
+}
+
+// Replace the printf-style placeholder (%d or eg. %04d) in a pattern with a number
+fn format_index(pattern: &str, index: u32) -> Option<String> {
+    let (prefix, rest) = pattern.split_once('%')?;
+    let (spec, suffix) = rest.split_once('d')?;
+
+    // the space flag leaves room for a sign, which the numbers never have
+    let (space, spec) = match spec.strip_prefix(' ') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    if !spec.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // a leading zero pads with zeros, otherwise the number is padded with spaces like in printf
+    let width: usize = if spec.is_empty() { 0 } else { spec.parse().ok()? };
+    let (sign, width) = if space { (" ", width.saturating_sub(1)) } else { ("", width) };
+    let number = if spec.starts_with('0') { format!("{index:0width$}") } else { format!("{index:width$}") };
+
+    Some(format!("{prefix}{sign}{number}{suffix}"))
+}
+
+// Whether a file name matches a glob pattern, where * matches any number of characters and ? any one character.
+// Only the last * is ever retried with a longer match, so this takes at most (pattern length × name length)
+// steps instead of exponential time in the number of stars.
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last * in the pattern, and the position in the name it was matched up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            // let the last * match one more character and try again from there
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, n));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    // the rest of the pattern has to match the empty string
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// A rectangle of detector pixels, eg. a region of the projections that only contains air
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PixelRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Aborts tasks when it is dropped, eg. to stop loading images when loading a scan is cancelled.
+// Aborting tasks that have already finished does nothing.
+struct AbortOnDrop(Vec<task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+// A problem with a scan descriptor or one of the images it refers to
+#[derive(Debug)]
+pub enum LoadError {
+    Read(PathBuf, io::Error),
+    MissingFile(PathBuf),
+    // the descriptor isn't valid JSON or is missing fields
+    Schema(PathBuf, serde_json::Error),
+    NoProjections(PathBuf),
+    // a pattern of projection file names that can't be used, and why
+    Pattern(String, String),
+    // a value in the descriptor that can't be right, and why
+    InvalidValue {
+        field: &'static str,
+        reason: String,
+    },
+    UnsupportedFormat(PathBuf),
+    Decode(PathBuf, ImageError),
+    // the dark and flat images and the bad pixel mask need to have the same dimensions as the projections
+    Dimensions {
+        path: PathBuf,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+}
+
+impl LoadError {
+    fn from_io(path: PathBuf, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => LoadError::MissingFile(path),
+            _ => LoadError::Read(path, err),
+        }
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Read(path, err) => write!(f, "Couldn't read {}: {err}", path.display()),
+            LoadError::MissingFile(path) => write!(f, "{} doesn't exist", path.display()),
+            LoadError::Schema(path, err) => write!(f, "{} isn't a valid scan descriptor: {err}", path.display()),
+            LoadError::NoProjections(path) => write!(f, "{} doesn't list any projections", path.display()),
+            LoadError::Pattern(pattern, reason) => write!(f, "Invalid projection pattern {pattern}: {reason}"),
+            LoadError::InvalidValue { field, reason } => write!(f, "Invalid {field}: {reason}"),
+            LoadError::UnsupportedFormat(path) => write!(f, "{} isn't in a supported image format", path.display()),
+            LoadError::Decode(path, err) => write!(f, "Couldn't decode {}: {err}", path.display()),
+            LoadError::Dimensions { path, expected, found } => write!(
+                f,
+                "{} is {}×{} pixels, but the projections are {}×{} pixels",
+                path.display(), found.0, found.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CtScan {
     pub name: String,
     pub direction: RotationDirection,
     pub sod: f32, // source-object-distance
     pub sdd: f32, // source-detector-distance
     pub swept_angle: f32,
+    // angle of the first projection in degrees, for projections without an explicit angle
+    #[serde(default)]
+    pub start_angle: f32,
     pub pixel_size: f32,
+    // horizontal offset of the detector center from the projection of the rotation axis, in mm
+    #[serde(default)]
+    pub detector_offset: f32,
+    // height of the detector center and the source above the center of the volume, in mm
+    #[serde(default)]
+    pub detector_vertical_offset: f32,
+    #[serde(default)]
+    pub source_vertical_offset: f32,
+    // rotation of the detector around the central ray, its horizontal axis and its vertical axis, in degrees
+    #[serde(default)]
+    pub detector_roll: f32,
+    #[serde(default)]
+    pub detector_pitch: f32,
+    #[serde(default)]
+    pub detector_yaw: f32,
 
     #[serde(rename = "projections")]
-    pub projection_files: Vec<PathBuf>,
+    pub projection_list: ProjectionList,
+    // directory the images are loaded from, relative to the descriptor unless it is absolute
+    #[serde(default, rename = "projection_dir", skip_serializing_if = "Option::is_none")]
+    pub projection_dir: Option<PathBuf>,
+    // images taken without the beam (dark current) and without an object in the beam (flat field)
+    #[serde(default, rename = "darks", skip_serializing_if = "Vec::is_empty")]
+    pub dark_files: Vec<PathBuf>,
+    #[serde(default, rename = "flats", skip_serializing_if = "Vec::is_empty")]
+    pub flat_files: Vec<PathBuf>,
+    // image where the dead and hot pixels of the detector are non-zero,
+    // they are detected automatically if it isn't given
+    #[serde(default, rename = "bad_pixels", skip_serializing_if = "Option::is_none")]
+    pub bad_pixel_file: Option<PathBuf>,
+    // region of the projections where the beam doesn't pass through the object,
+    // it is detected automatically if it isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub air_region: Option<PixelRegion>,
+    // coefficients of the polynomial c₀p + c₁p² + ... that corrects the line integrals p for beam hardening
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub beam_hardening: Vec<f32>,
 
+    // the projections listed in the descriptor or matching its pattern
+    #[serde(skip)]
+    pub projection_files: Vec<ProjectionFile>,
+    // shared, so changing the geometry of a loaded scan doesn't copy the images
+    #[serde(skip)]
+    pub projection_images: Arc<[ScanImage]>,
+    // the average of the dark and flat images, if there are any
+    #[serde(skip)]
+    pub dark_image: Option<Arc<ScanImage>>,
     #[serde(skip)]
-    pub projection_images: Vec<ScanImage>,
+    pub flat_image: Option<Arc<ScanImage>>,
+    // the file the scan was loaded from
+    #[serde(skip)]
+    pub descriptor_path: PathBuf,
+    // the projections after the last denoising, shared between copies of the scan as it doesn't depend on the geometry
+    #[serde(skip)]
+    pub denoised: Arc<DenoiseCache>,
+    // the last automatic beam hardening correction, which only depends on the projections and the geometry
+    #[serde(skip)]
+    pub beam_hardening_fit: Arc<FitCache>,
 }
 
 // implement Debug for ScanDescriptor but don't print images_files and images as this takes a _long_ time (especially for the latter)
@@ -48,49 +329,327 @@ impl Debug for CtScan {
             .field("sod", &self.sod)
             .field("sdd", &self.sdd)
             .field("swept_angle", &self.swept_angle)
+            .field("start_angle", &self.start_angle)
             .field("pixel_size", &self.pixel_size)
-            .field("projection_files", &"...")
+            .field("detector_offset", &self.detector_offset)
+            .field("detector_vertical_offset", &self.detector_vertical_offset)
+            .field("source_vertical_offset", &self.source_vertical_offset)
+            .field("detector_roll", &self.detector_roll)
+            .field("detector_pitch", &self.detector_pitch)
+            .field("detector_yaw", &self.detector_yaw)
+            .field("projection_list", &"...")
+            .field("projection_dir", &self.projection_dir)
+            .field("dark_files", &self.dark_files)
+            .field("flat_files", &self.flat_files)
+            .field("bad_pixel_file", &self.bad_pixel_file)
+            .field("air_region", &self.air_region)
+            .field("beam_hardening", &self.beam_hardening)
+            .field("descriptor_path", &self.descriptor_path)
             .field("images", &"...")
             .finish()
     }
 }
 
 impl CtScan {
-    pub async fn from_file(path: impl Into<PathBuf>) -> io::Result<Self> {
+    // Load a scan descriptor and all the images it refers to. Every problem with the images is
+    // collected, so they can all be fixed at once instead of finding them one by one.
+    // `progress` is called with the number of images loaded so far and the total number of images
+    // after each image. Dropping the returned future cancels the images that are still loading.
+    pub async fn from_file(
+        path: impl Into<PathBuf>,
+        progress: impl Fn(usize, usize) + Send + Sync,
+    ) -> Result<Self, Vec<LoadError>> {
         let path = path.into();
 
-        let file_contents = tokio::fs::read(&path).await?;
+        let file_contents = tokio::fs::read(&path).await
+            .map_err(|err| vec![LoadError::from_io(path.clone(), err)])?;
         let mut parsed = Self {
-            ..serde_json::from_slice(&file_contents)?
+            descriptor_path: path.clone(),
+            ..serde_json::from_slice(&file_contents).map_err(|err| vec![LoadError::Schema(path.clone(), err)])?
+        };
+
+        let images_dir = parsed.images_dir();
+        parsed.projection_files = parsed.projection_list.files(&images_dir).await.map_err(|err| vec![err])?;
+        if parsed.projection_files.is_empty() {
+            return Err(vec![LoadError::NoProjections(path)]);
+        }
+
+        // the images are still loaded when the geometry is invalid, so all problems are found at once
+        let mut errors = parsed.validate();
+
+        let total = parsed.projection_files.len() + parsed.dark_files.len() + parsed.flat_files.len()
+            + parsed.bad_pixel_file.iter().len();
+        let mut loaded = 0;
+        let mut on_loaded = || {
+            loaded += 1;
+            progress(loaded, total);
         };
 
-        parsed.projection_images = Self::load_images(&path, parsed.projection_files.clone()).await?;
+        let projection_paths = parsed.projection_files.iter().map(|file| file.path().to_owned()).collect();
+        let projection_images = Self::load_images(&images_dir, projection_paths, None, &mut errors, &mut on_loaded).await;
+
+        // the other images are compared with the projections
+        let dimensions = projection_images.first().map(|image| image.dimensions());
+        let dark_images = Self::load_images(&images_dir, parsed.dark_files.clone(), dimensions, &mut errors, &mut on_loaded).await;
+        let flat_images = Self::load_images(&images_dir, parsed.flat_files.clone(), dimensions, &mut errors, &mut on_loaded).await;
+        let mask_files = parsed.bad_pixel_file.iter().cloned().collect();
+        let mask_images = Self::load_images(&images_dir, mask_files, dimensions, &mut errors, &mut on_loaded).await;
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // replace the bad pixels before anything else, so they don't spread into their neighbours.
+        // This takes a while for large scans, so it runs on a blocking thread and is abandoned when this future is dropped.
+        let (projection_images, dark_image, flat_image) = task::spawn_blocking(move || {
+            let (mut projections, mut dark, mut flat) = (projection_images, Self::average(&dark_images), Self::average(&flat_images));
+            Self::correct_bad_pixels(&mut projections, [&mut dark, &mut flat], mask_images.first());
+            (projections, dark, flat)
+        })
+        .await
+        .map_err(|err| vec![LoadError::Read(path.clone(), io::Error::other(err))])?;
+
+        parsed.dark_image = dark_image;
+        parsed.flat_image = flat_image;
+        parsed.projection_images = projection_images.into();
 
         Ok(parsed)
     }
 
-    async fn load_images(file_path: impl AsRef<Path>, image_files: Vec<PathBuf>) -> io::Result<Vec<ScanImage>> {
-        let images_dir = file_path.as_ref().parent().unwrap().join("projections");
+    // Change the rotation direction in the descriptor file. The file is read again and only the
+    // direction is replaced, so other edits of the loaded scan and keys this version doesn't know are left alone.
+    pub async fn write_direction(path: &Path, direction: RotationDirection) -> io::Result<()> {
+        let mut descriptor: serde_json::Value = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        let Some(fields) = descriptor.as_object_mut() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the descriptor isn't a JSON object"));
+        };
+        fields.insert("direction".into(), serde_json::to_value(direction)?);
+
+        tokio::fs::write(path, serde_json::to_string_pretty(&descriptor)?).await
+    }
+
+    // The values of the descriptor that the geometry can't be built from
+    fn validate(&self) -> Vec<LoadError> {
+        let checks = [
+            ("pixel_size", self.pixel_size > 0., format!("{} mm isn't larger than 0", self.pixel_size)),
+            ("sod", self.sod > 0., format!("{} mm isn't larger than 0", self.sod)),
+            ("sdd", self.sdd > self.sod, format!("{} mm isn't larger than the sod of {} mm", self.sdd, self.sod)),
+            ("swept_angle", self.swept_angle != 0. && self.swept_angle.is_finite(), format!("{}° is no rotation", self.swept_angle)),
+        ];
+
+        checks.into_iter()
+            .filter(|(_, valid, _)| !valid)
+            .map(|(field, _, reason)| LoadError::InvalidValue { field, reason })
+            .collect()
+    }
+
+    // Replace the bad pixels in the projections and the dark and flat images. They are read from the
+    // mask if there is one and detected in the projections otherwise.
+    fn correct_bad_pixels(projection_images: &mut [ScanImage], others: [&mut Option<Arc<ScanImage>>; 2], mask: Option<&ScanImage>) {
+        let bad_pixels = match mask {
+            Some(mask) => mask.iter().map(|sample| *sample > 0.).collect(),
+            None => bad_pixels::detect(projection_images),
+        };
+        let n_bad_pixels = bad_pixels.iter().filter(|bad| **bad).count();
+        if n_bad_pixels == 0 {
+            return;
+        }
+
+        log::info!("Correcting {n_bad_pixels} bad pixels");
+
+        projection_images.par_iter_mut().for_each(|image| bad_pixels::correct(image, &bad_pixels));
+        for image in others.into_iter().flatten() {
+            bad_pixels::correct(Arc::make_mut(image), &bad_pixels);
+        }
+    }
+
+    // The directory the images of the scan are in, `projections` next to the descriptor by default
+    pub fn images_dir(&self) -> PathBuf {
+        let descriptor_dir = self.descriptor_path.parent().unwrap_or(Path::new(""));
+
+        // joining an absolute path replaces the descriptor directory
+        descriptor_dir.join(self.projection_dir.as_deref().unwrap_or(Path::new("projections")))
+    }
+
+    // The angle (in degrees) of the turntable for each projection. Projections without an
+    // explicit angle are spread evenly over the swept angle, starting at the start angle.
+    pub fn projection_angles(&self) -> Vec<f32> {
+        let n_projections = self.projection_files.len();
+
+        self.projection_files.iter().enumerate()
+            .map(|(i, file)| file.angle().unwrap_or(
+                self.start_angle + (i as f32)*self.swept_angle/(n_projections as f32)
+            ))
+            .collect()
+    }
+
+    // The angle (in degrees) between neighbouring projections, the median for unevenly spaced explicit angles
+    pub fn angle_step(&self) -> f32 {
+        let angles = self.projection_angles();
+        let mut steps: Vec<f32> = angles.windows(2).map(|pair| (pair[1] - pair[0]).abs()).collect();
+        if steps.is_empty() {
+            return self.swept_angle.abs();
+        }
+
+        let middle = steps.len() / 2;
+        *steps.select_nth_unstable_by(middle, f32::total_cmp).1
+    }
+
+    // The angle (in degrees) the projections cover in either direction, from the first projection to
+    // a step past the last one, so it is the swept angle for evenly spaced projections
+    pub fn sweep(&self) -> f32 {
+        let angles = self.projection_angles();
+        let Some(first) = angles.first() else {
+            return 0.;
+        };
+
+        angles.iter().map(|angle| (angle - first).abs()).fold(0., f32::max) + self.angle_step()
+    }
+
+    // Average images, eg. to reduce the noise in dark and flat images
+    fn average(images: &[ScanImage]) -> Option<Arc<ScanImage>> {
+        let first = images.first()?;
+
+        let mut average = ImageBuffer::new(first.width(), first.height());
+        for image in images {
+            for (sum, sample) in average.iter_mut().zip(image.iter()) {
+                *sum += sample / images.len() as f32;
+            }
+        }
+
+        Some(Arc::new(average))
+    }
+
+    // Load images and check that they have the given dimensions, or the dimensions of the first image
+    // that could be loaded if there are none. Only the images without problems are returned, the
+    // problems with the others are added to `errors`. `on_loaded` is called as each image finishes.
+    async fn load_images(
+        images_dir: &Path,
+        image_files: Vec<PathBuf>,
+        dimensions: Option<(u32, u32)>,
+        errors: &mut Vec<LoadError>,
+        on_loaded: &mut (impl FnMut() + Send),
+    ) -> Vec<ScanImage> {
+        let full_paths: Vec<PathBuf> = image_files.iter().map(|filename| images_dir.join(filename)).collect();
 
-        let full_paths = image_files.iter().map(|filename| images_dir.join(filename));
+        // the images are decoded in parallel, and the decoding stops if this future is dropped
+        let handles: Vec<_> = full_paths.iter().cloned().map(|path| task::spawn(Self::load_image(path))).collect();
+        let _abort = AbortOnDrop(handles.iter().map(|handle| handle.abort_handle()).collect());
 
-        let image_load_tasks = full_paths.map(|path| {
-            task::spawn(async move {
-                let bytes = tokio::fs::read(path).await.unwrap();
+        let mut pending: FuturesUnordered<_> = handles.into_iter().enumerate()
+            .map(|(i, handle)| handle.map(move |result| (i, result)))
+            .collect();
 
-                ImageReader::new(Cursor::new(bytes))
-                    .with_guessed_format()
-                    .unwrap()
-                    .decode()
-                    .unwrap()
-                    .to_luma32f()
-            })
-        });
+        let mut results: Vec<Option<Result<ScanImage, LoadError>>> = full_paths.iter().map(|_| None).collect();
+        while let Some((i, result)) = pending.next().await {
+            results[i] = Some(result.unwrap_or_else(|err| Err(LoadError::Read(full_paths[i].clone(), io::Error::other(err)))));
+            on_loaded();
+        }
 
-        let images = future::try_join_all(image_load_tasks).await?;
+        let mut dimensions = dimensions;
+        let mut images = Vec::with_capacity(results.len());
+        for (path, result) in full_paths.into_iter().zip(results.into_iter().flatten()) {
+            let image = match result {
+                Ok(image) => image,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                },
+            };
 
-        Ok(images)
+            let expected = *dimensions.get_or_insert(image.dimensions());
+            if image.dimensions() != expected {
+                errors.push(LoadError::Dimensions { path, expected, found: image.dimensions() });
+                continue;
+            }
+
+            images.push(image);
+        }
+
+        images
+    }
+
+    async fn load_image(path: PathBuf) -> Result<ScanImage, LoadError> {
+        let bytes = tokio::fs::read(&path).await.map_err(|err| LoadError::from_io(path.clone(), err))?;
+
+        let reader = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|err| LoadError::Read(path.clone(), err))?;
+        if reader.format().is_none() {
+            return Err(LoadError::UnsupportedFormat(path));
+        }
+
+        match reader.decode() {
+            Ok(image) => Ok(image.to_luma32f()),
+            Err(ImageError::Unsupported(_)) => Err(LoadError::UnsupportedFormat(path)),
+            Err(err) => Err(LoadError::Decode(path, err)),
+        }
     }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        glob_matches(&pattern.chars().collect::<Vec<char>>(), &name.chars().collect::<Vec<char>>())
+    }
+
+    #[test]
+    fn format_index_fills_in_printf_placeholders() {
+        assert_eq!(format_index("projection%d.tif", 7).as_deref(), Some("projection7.tif"));
+        assert_eq!(format_index("projection%04d.tif", 7).as_deref(), Some("projection0007.tif"));
+        assert_eq!(format_index("projection%04d.tif", 12345).as_deref(), Some("projection12345.tif"));
+        assert_eq!(format_index("projection%4d.tif", 7).as_deref(), Some("projection   7.tif"));
+        assert_eq!(format_index("projection% 4d.tif", 7).as_deref(), Some("projection   7.tif"));
+        assert_eq!(format_index("projection% 4d.tif", 12345).as_deref(), Some("projection 12345.tif"));
+
+        assert_eq!(format_index("projection.tif", 7), None);
+        assert_eq!(format_index("projection%s.tif", 7), None);
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob("projection*.tif", "projection0001.tif"));
+        assert!(glob("projection*.tif", "projection.tif"));
+        assert!(!glob("projection*.tif", "projection0001.png"));
+
+        assert!(glob("projection????.tif", "projection0001.tif"));
+        assert!(!glob("projection????.tif", "projection001.tif"));
+
+        // a double star is the same as a single one, as there are no subdirectories to match
+        assert!(glob("**.tif", "projection0001.tif"));
+        assert!(glob("projection**.tif", "projection.tif"));
+
+        // a trailing star matches the rest of the name, including nothing
+        assert!(glob("projection*", "projection0001.tif"));
+        assert!(glob("projection*", "projection"));
+        assert!(!glob("projection*", "flat0001.tif"));
+
+        // many stars against a long name that doesn't match would take exponential time with naive backtracking
+        assert!(!glob("*a*a*a*a*a*a*a*a*a*a*b", &"a".repeat(200)));
+    }
+
+    #[test]
+    fn glob_pattern_needs_enough_files_for_its_count() {
+        let images_dir = std::env::temp_dir().join(format!("ct-rs-glob-{}", std::process::id()));
+        std::fs::create_dir_all(&images_dir).unwrap();
+        for i in 0..3 {
+            std::fs::write(images_dir.join(format!("projection{i}.tif")), []).unwrap();
+        }
+
+        let files = |start, count| {
+            let list = ProjectionList::Pattern { pattern: "projection*.tif".into(), start, count };
+            tokio::runtime::Runtime::new().unwrap().block_on(list.files(&images_dir))
+        };
+
+        assert_eq!(files(1, Some(2)).unwrap().len(), 2);
+        assert_eq!(files(1, None).unwrap().len(), 2);
+        assert!(matches!(files(1, Some(3)), Err(LoadError::Pattern(..))));
+        assert!(matches!(files(4, Some(1)), Err(LoadError::Pattern(..))));
+
+        std::fs::remove_dir_all(&images_dir).unwrap();
+    }
+}
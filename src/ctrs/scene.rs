@@ -1,33 +1,83 @@
-mod pipeline;
+pub mod pipeline;
 
-use std::{f32::consts::PI, sync::{Arc, RwLock}};
+use std::sync::{Arc, RwLock};
 
 use iced::{mouse, widget::shader};
 use iced_wgpu::wgpu;
-use pipeline::{uniforms::{Camera, Projection}, Pipeline};
+use pipeline::{uniforms::{Camera, Misalignment, Projection, ProjectionTiles, Reconstruction}, Pipeline};
+
+use super::{reconstruction::{ReconstructionMode, Volume}, scan::{CtScan, ScanImage}};
+
+// edge length of the reconstructed volume in world units (mm)
+pub const VOLUME_SIZE: f32 = 60.;
+
+// the geometry of each projection in a scan
+pub fn projection_geometry(scan: &CtScan) -> Arc<[Projection]> {
+    let rot_dir = scan.direction.dir();
+    let misalignment = Misalignment {
+        detector_offset: scan.detector_offset,
+        detector_vertical_offset: scan.detector_vertical_offset,
+        source_vertical_offset: scan.source_vertical_offset,
+        detector_roll: scan.detector_roll.to_radians(),
+        detector_pitch: scan.detector_pitch.to_radians(),
+        detector_yaw: scan.detector_yaw.to_radians(),
+    };
+    // physical size of the detector, the projections all have the same dimensions
+    let (width, height) = scan.projection_images.first().map_or((0, 0), |image| image.dimensions());
+    let detector_dimensions = (width as f32 * scan.pixel_size, height as f32 * scan.pixel_size);
+
+    scan.projection_angles().into_iter()
+        .map(|angle| Projection::new(
+                rot_dir * angle.to_radians(),
+                scan.sod,
+                scan.sdd,
+                detector_dimensions,
+                &misalignment,
+            )
+        )
+        .collect()
+}
+
+// Whether the projections fit in a texture on any device, with the lowest limits that iced asks for.
+// Otherwise they have to be back-projected on the CPU.
+pub fn projections_fit_on_gpu(projection_data: &[ScanImage]) -> bool {
+    let tile_size = projection_data.first().map_or((1, 1), |image| image.dimensions());
 
-use super::scan::CtScan;
+    ProjectionTiles::new(tile_size, projection_data.len() as u32, &wgpu::Limits::downlevel_defaults()).is_some()
+}
+
+// what the rendered volume is made from
+#[derive(Debug, Clone)]
+pub enum VolumeSource {
+    // preprocessed projection images that are back-projected on the GPU
+    Projections(Arc<[ScanImage]>),
+    // a volume that has already been reconstructed on the CPU
+    Volume(Arc<Volume>),
+}
 
 #[derive(Debug)]
 pub struct Primitive {
-    scan: Arc<CtScan>,
+    source: VolumeSource,
     projections: Arc<[Projection]>,
+    reconstruction_uniform: Reconstruction,
     camera_uniform: Camera,
     new_scene: bool,
 }
 
 impl Primitive {
     fn new(
-        scan: Arc<CtScan>,
+        source: VolumeSource,
         projections: Arc<[Projection]>,
+        reconstruction_uniform: Reconstruction,
         inclination: f32,
         threshold: f32,
         new_scene: bool
     ) -> Self {
         Self {
-            scan,
+            source,
             projections,
             new_scene,
+            reconstruction_uniform,
             camera_uniform: Camera::new(
                 40.,
                 inclination,
@@ -49,16 +99,33 @@ impl shader::Primitive for Primitive {
         _bounds: &iced::Rectangle,
         _viewport: &shader::Viewport,
     ) {
-        // (re)create the pipeline if it doesn't exist or we have switched to a new scene
+        // (re)create the pipeline and reconstruct the volume if it doesn't exist
+        // or we have switched to a new scene
         if !storage.has::<Pipeline>() || self.new_scene {
             log::info!("Creating pipeline!");
+            let volume_texture = match &self.source {
+                VolumeSource::Projections(projection_data) => pipeline::backprojection::reconstruct_volume(
+                    device,
+                    queue,
+                    projection_data,
+                    &self.projections,
+                    &self.reconstruction_uniform,
+                )
+                .unwrap_or_else(|| {
+                    // `projections_fit_on_gpu` is checked before the projections are handed to the scene,
+                    // so this only happens on devices with lower limits than iced asks for
+                    log::error!("The projections don't fit in a texture on this device");
+                    let [width, height, depth] = self.reconstruction_uniform.volume_dimensions;
+                    pipeline::upload_volume(device, queue, &Volume::new((width, height, depth), VOLUME_SIZE))
+                }),
+                VolumeSource::Volume(volume) => pipeline::upload_volume(device, queue, volume),
+            };
+
             let pipeline = Pipeline::new(
                 device,
                 &format,
-                queue,
-                &self.scan.projection_images,
-                (500,500,256), // TODO: don't have constant dimensions here
-                &self.projections,
+                &volume_texture,
+                &self.reconstruction_uniform,
             );
 
             storage.store(pipeline);
@@ -81,31 +148,28 @@ impl shader::Primitive for Primitive {
 }
 
 pub struct Scene {
-    scan: Arc<CtScan>,
+    source: VolumeSource,
     projections: Arc<[Projection]>,
+    mode: ReconstructionMode,
+    volume_resolution: u32,
     inclination: f32,
     threshold: f32,
     new_scene: RwLock<bool>
 }
 
 impl Scene {
-    pub fn new(scan: Arc<CtScan>, threshold: f32) -> Self {
-        let rot_dir = scan.direction.dir();
-
-        let n_projections = scan.projection_images.len();
-        let projections = (0..n_projections).into_iter()
-            .map(|i| Projection::new(
-                    rot_dir * (i as f32)*(scan.swept_angle*PI/180.)/(n_projections as f32),
-                    scan.sod,
-                    scan.sdd,
-                    (500.*scan.pixel_size, 500.*scan.pixel_size)
-                )
-            )
-            .collect();
-        
+    pub fn new(
+        scan: &CtScan,
+        source: VolumeSource,
+        mode: ReconstructionMode,
+        volume_resolution: u32,
+        threshold: f32
+    ) -> Self {
         Self {
-            scan,
-            projections,
+            source,
+            projections: projection_geometry(scan),
+            mode,
+            volume_resolution,
             inclination: 0.,
             threshold,
             new_scene: RwLock::from(true),
@@ -119,6 +183,33 @@ impl Scene {
     pub fn set_threshold(&mut self, threshold: f32) {
         self.threshold = threshold;
     }
+
+    // changing the voxel grid requires the volume to be reconstructed again
+    pub fn set_volume_resolution(&mut self, volume_resolution: u32) {
+        self.volume_resolution = volume_resolution;
+        *self.new_scene.write().unwrap() = true;
+    }
+
+    // whether the scene shows a volume that was reconstructed on the CPU
+    pub fn has_volume(&self) -> bool {
+        matches!(self.source, VolumeSource::Volume(_))
+    }
+
+    pub fn set_source(&mut self, source: VolumeSource) {
+        self.source = source;
+        *self.new_scene.write().unwrap() = true;
+    }
+
+    fn reconstruction_uniform(&self) -> Reconstruction {
+        match &self.source {
+            VolumeSource::Projections(_) => Reconstruction::new(
+                (self.volume_resolution, self.volume_resolution, self.volume_resolution),
+                VOLUME_SIZE,
+                self.mode.is_cone_beam_weighted()
+            ),
+            VolumeSource::Volume(volume) => Reconstruction::new(volume.dimensions, volume.size, false),
+        }
+    }
 }
 
 impl<Message> shader::Program<Message> for Scene {
@@ -134,8 +225,9 @@ impl<Message> shader::Program<Message> for Scene {
         let mut new_scene = self.new_scene.write().unwrap();
 
         let primitive = Primitive::new(
-            self.scan.clone(),
+            self.source.clone(),
             self.projections.clone(),
+            self.reconstruction_uniform(),
             self.inclination,
             self.threshold,
             *new_scene,
@@ -1,12 +1,13 @@
+pub mod backprojection;
 pub mod uniforms;
 pub mod vertex;
 
 use iced::Rectangle;
 use iced_wgpu::wgpu::{self, util::DeviceExt};
-use uniforms::{Camera, Projection};
+use uniforms::{Camera, Reconstruction};
 use vertex::Vertex;
 
-use crate::ctrs::scan::ScanImage;
+use crate::ctrs::reconstruction::Volume;
 
 const VERTICES: &[Vertex; 4] = &[
     Vertex { position: [-1.0,  1.0], cam_coords: [-1.0,  1.0] }, // top left
@@ -29,80 +30,45 @@ pub struct Pipeline {
     camera_uniform_buffer: wgpu::Buffer,
 
     camera_bind_group: wgpu::BindGroup,
-    projections_bind_group: wgpu::BindGroup,
+    volume_bind_group: wgpu::BindGroup,
 }
 
 impl Pipeline {
+    // `volume_texture` is the reconstructed volume that is rendered,
+    // see `backprojection::reconstruct_volume` and `upload_volume`
     pub fn new(
         device: &wgpu::Device, 
         texture_format: &wgpu::TextureFormat,
-        queue: &wgpu::Queue,
-        projection_images: &[ScanImage],
-        proj_extent: (u32,u32,u32),
-        projections: &[Projection],
+        volume_texture: &wgpu::Texture,
+        reconstruction: &Reconstruction,
     ) -> Self {
-        let buf_size: usize = projection_images.iter().map(|img| img.len()).sum();
-
-        let mut transformed_texture_data: Vec<f32> = Vec::with_capacity(buf_size);
-        for proj in projection_images {
-            transformed_texture_data.extend(proj.iter().map(|sample| -sample.ln()));
-        }
-
-        let max: f32 = transformed_texture_data.iter().copied().reduce(|prev, cur| prev.max(cur)).unwrap();
-        let normalized_texture_data: Vec<f32> = transformed_texture_data.iter().map(|sample| sample/max).collect();
-
-        // TODO: handle differing image sizes (maybe not here, but in CtScan::load_images)
-        let projections_extent = wgpu::Extent3d {
-            width: proj_extent.0,
-            height: proj_extent.1,
-            depth_or_array_layers: proj_extent.2,
-        };
-
-        let projections_texture = device.create_texture_with_data(
-            queue,
-            &wgpu::TextureDescriptor {
-                label: Some("Projections texture"),
-                size: projections_extent,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::R32Float,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                view_formats: &[],
-            },
-            wgpu::util::TextureDataOrder::LayerMajor,
-            bytemuck::cast_slice(&normalized_texture_data)
-
-        );
-
-        let projections_view = projections_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let projections_sampler = device.create_sampler(&wgpu::SamplerDescriptor{
-            label: Some("Projections texture sampler"),
+        let volume_view = volume_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let volume_sampler = device.create_sampler(&wgpu::SamplerDescriptor{
+            label: Some("Volume texture sampler"),
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Nearest,
             min_filter: wgpu::FilterMode::Nearest,
             mipmap_filter: wgpu::FilterMode::Nearest,
-            // border_color: Some(wgpu::SamplerBorderColor::OpaqueBlack),
             ..Default::default()
         });
 
-        let projections_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Projections storage buffer"),
-            usage: wgpu::BufferUsages::STORAGE,
-            contents: bytemuck::cast_slice(&projections) //&projections_wgsl,
+        let reconstruction_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Reconstruction uniform buffer"),
+            usage: wgpu::BufferUsages::UNIFORM,
+            contents: bytemuck::cast_slice(&[*reconstruction]),
         });
 
-        let projections_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Projections texture bind group layout"),
+        let volume_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Volume texture bind group layout"),
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        view_dimension: wgpu::TextureViewDimension::D3,
                         multisampled: false,
                     },
                     count: None,
@@ -117,7 +83,7 @@ impl Pipeline {
                     binding: 2,
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
@@ -126,21 +92,21 @@ impl Pipeline {
             ],
         });
 
-        let projections_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Projections texture bind group"),
-            layout: &projections_bind_group_layout,
+        let volume_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Volume texture bind group"),
+            layout: &volume_bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&projections_view),
+                    resource: wgpu::BindingResource::TextureView(&volume_view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&projections_sampler),
+                    resource: wgpu::BindingResource::Sampler(&volume_sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: projections_buffer.as_entire_binding(),
+                    resource: reconstruction_buffer.as_entire_binding(),
                 }
             ],
         });
@@ -197,7 +163,7 @@ impl Pipeline {
             label: Some("Camera bind group layout"),
             push_constant_ranges: &[],
             bind_group_layouts: &[
-                &projections_bind_group_layout,
+                &volume_bind_group_layout,
                 &camera_bind_group_layout
             ],
         });
@@ -257,7 +223,7 @@ impl Pipeline {
             index_buffer,
             camera_uniform_buffer,
             camera_bind_group,
-            projections_bind_group,
+            volume_bind_group,
         }
     }
 
@@ -297,7 +263,7 @@ impl Pipeline {
 
         pass.set_pipeline(&self.pipeline);
 
-        pass.set_bind_group(0, &self.projections_bind_group, &[]);
+        pass.set_bind_group(0, &self.volume_bind_group, &[]);
         pass.set_bind_group(1, &self.camera_bind_group, &[]);
 
         pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
@@ -305,4 +271,27 @@ impl Pipeline {
         
         pass.draw_indexed(0..6, 0, 0..1);
     }
-}
\ No newline at end of file
+}
+
+// Create a volume texture from a volume that has been reconstructed on the CPU
+pub fn upload_volume(device: &wgpu::Device, queue: &wgpu::Queue, volume: &Volume) -> wgpu::Texture {
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Volume texture"),
+            size: wgpu::Extent3d {
+                width: volume.dimensions.0,
+                height: volume.dimensions.1,
+                depth_or_array_layers: volume.dimensions.2,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&volume.data)
+    )
+}
@@ -0,0 +1,222 @@
+use iced_wgpu::wgpu::{self, util::DeviceExt};
+
+use crate::ctrs::scan::ScanImage;
+
+use super::uniforms::{Projection, ProjectionTiles, Reconstruction};
+
+// must match the workgroup size in reconstruct.wgsl
+const WORKGROUP_SIZE: u32 = 4;
+
+// Back-project the (preprocessed) projections into a new 3D texture with the
+// dimensions given in the reconstruction uniform, using a compute shader.
+// Returns None if the projections don't fit in a texture on this device.
+pub fn reconstruct_volume(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    projection_images: &[ScanImage],
+    projections: &[Projection],
+    reconstruction: &Reconstruction,
+) -> Option<wgpu::Texture> {
+    // the projections all have the same dimensions, see `CtScan::from_file`
+    let tile_size = projection_images.first().map_or((1, 1), |image| image.dimensions());
+    let n_projections = projection_images.len() as u32;
+    let tiles = ProjectionTiles::new(tile_size, n_projections, &device.limits())?;
+
+    // the images have already been preprocessed, so they only need to be packed into the tiles
+    let (layer_width, layer_height) = tiles.layer_size();
+    let layers = tiles.layers(n_projections);
+    let mut texture_data = vec![0f32; (layer_width * layer_height * layers) as usize];
+    for (index, image) in projection_images.iter().enumerate() {
+        let (layer, x, y) = tiles.origin(index as u32);
+        for (row, samples) in image.chunks_exact(tile_size.0 as usize).enumerate() {
+            let start = ((layer * layer_height + y + row as u32) * layer_width + x) as usize;
+            texture_data[start..start + samples.len()].copy_from_slice(samples);
+        }
+    }
+
+    let projections_extent = wgpu::Extent3d {
+        width: layer_width,
+        height: layer_height,
+        depth_or_array_layers: layers,
+    };
+
+    let projections_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Projections texture"),
+            size: projections_extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&texture_data)
+    );
+
+    // a view with a single layer would be a 2D view by default
+    let projections_view = projections_texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+
+    let tiles_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Projection tiles uniform buffer"),
+        usage: wgpu::BufferUsages::UNIFORM,
+        contents: bytemuck::cast_slice(&[tiles]),
+    });
+
+    let projections_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Projections storage buffer"),
+        usage: wgpu::BufferUsages::STORAGE,
+        contents: bytemuck::cast_slice(projections),
+    });
+
+    let reconstruction_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Reconstruction uniform buffer"),
+        usage: wgpu::BufferUsages::UNIFORM,
+        contents: bytemuck::cast_slice(&[*reconstruction]),
+    });
+
+    let [width, height, depth] = reconstruction.volume_dimensions;
+    let volume_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Volume texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: depth,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        format: wgpu::TextureFormat::R32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let volume_view = volume_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Back-projection bind group layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::R32Float,
+                    view_dimension: wgpu::TextureViewDimension::D3,
+                },
+                count: None,
+            }
+        ],
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Back-projection bind group"),
+        layout: &bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&projections_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: tiles_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: projections_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: reconstruction_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&volume_view),
+            }
+        ],
+    });
+
+    let shader_module = device.create_shader_module(wgpu::include_wgsl!("../../shaders/reconstruct.wgsl"));
+
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Back-projection pipeline layout"),
+        push_constant_ranges: &[],
+        bind_group_layouts: &[
+            &bind_group_layout,
+        ],
+    });
+
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Back-projection pipeline"),
+        layout: Some(&pipeline_layout),
+        module: &shader_module,
+        entry_point: "cs_main",
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Back-projection encoder"),
+    });
+
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Back-projection pass"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(
+            width.div_ceil(WORKGROUP_SIZE),
+            height.div_ceil(WORKGROUP_SIZE),
+            depth.div_ceil(WORKGROUP_SIZE),
+        );
+    }
+
+    queue.submit(Some(encoder.finish()));
+
+    Some(volume_texture)
+}
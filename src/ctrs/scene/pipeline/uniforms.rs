@@ -1,5 +1,7 @@
 use std::f32::consts::PI;
 
+use iced_wgpu::wgpu;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Camera {
@@ -44,74 +46,412 @@ impl Camera {
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Reconstruction {
+    // number of voxels along each axis of the reconstructed volume
+    pub volume_dimensions: [u32; 3],
+    // whether to weight back-projected values by 1/U² as in FDK
+    pub distance_weighting: u32,
+    // edge length of the (cubic) reconstructed volume in world units,
+    // the volume is centered on the world origin
+    pub volume_size: f32,
+    _padding: [u32; 3],
+}
+
+impl Reconstruction {
+    pub fn new(volume_dimensions: (u32, u32, u32), volume_size: f32, distance_weighting: bool) -> Self {
+        Self {
+            volume_dimensions: [volume_dimensions.0, volume_dimensions.1, volume_dimensions.2],
+            distance_weighting: distance_weighting as u32,
+            volume_size,
+
+            _padding: [0; 3],
+        }
+    }
+}
+
+// Where each projection is in the projections texture. There are more projections than a texture
+// array has layers in most scans, so each layer holds a grid of projections next to each other.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ProjectionTiles {
+    // dimensions of a projection in texels
+    pub tile_size: [u32; 2],
+    // number of projections along each row of a layer and in each layer
+    pub columns: u32,
+    pub per_layer: u32,
+}
+
+impl ProjectionTiles {
+    // Fit `n_projections` projections of the given dimensions into as few texture layers as the limits allow.
+    // Returns None if they don't fit even when every layer is filled up to the max texture dimensions.
+    pub fn new(tile_size: (u32, u32), n_projections: u32, limits: &wgpu::Limits) -> Option<Self> {
+        let (width, height) = tile_size;
+        let max_columns = limits.max_texture_dimension_2d / width.max(1);
+        let max_rows = limits.max_texture_dimension_2d / height.max(1);
+
+        let needed = n_projections.div_ceil(limits.max_texture_array_layers).max(1);
+        let columns = max_columns.min(needed);
+        let rows = needed.div_ceil(columns.max(1));
+        if columns == 0 || rows > max_rows {
+            return None;
+        }
+
+        Some(Self {
+            tile_size: [width, height],
+            columns,
+            per_layer: columns * rows,
+        })
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.per_layer / self.columns
+    }
+
+    pub fn layers(&self, n_projections: u32) -> u32 {
+        n_projections.div_ceil(self.per_layer).max(1)
+    }
+
+    // dimensions of each texture layer in texels
+    pub fn layer_size(&self) -> (u32, u32) {
+        (self.columns * self.tile_size[0], self.rows() * self.tile_size[1])
+    }
+
+    // the layer and the texel of the top left corner of the projection with the given index
+    pub fn origin(&self, index: u32) -> (u32, u32, u32) {
+        let tile = index % self.per_layer;
+
+        (
+            index / self.per_layer,
+            (tile % self.columns) * self.tile_size[0],
+            (tile / self.columns) * self.tile_size[1],
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Projection {
     // transform from world to sensor-plane coordinates
     pub translate: [f32; 3],
     _padding0: u32,
     pub transform: [[f32; 4]; 3], // 3x3 matrix, the 4 is for alignment with WGSL
+    // position of the source in sensor-plane coordinates
+    pub source: [f32; 3],
+    _padding1: u32,
     // transformation from projection coordinates to texture coordinates
     // this is a 3x2 matrix where the right-most column is a translation
     // to apply after the transformation (in texture space). The input should
     // be [proj.x, proj.y, 1]. The translation will be scaled by the last element.
     pub texture_transform: [[f32; 2]; 3],
 
-    // sensor to detector distance
+    // source to detector distance
     pub sdd: f32,
-    _padding1: u32,
+    // source to object (rotation axis) distance
+    pub sod: f32,
+}
+
+// How the source and detector deviate from the ideal geometry, where the detector is
+// perpendicular to the central ray and the source, the rotation axis and the detector
+// center are in the same horizontal plane. Distances are in mm and angles in radians.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Misalignment {
+    // horizontal offset of the detector center from the projected rotation axis, along the detector
+    pub detector_offset: f32,
+    // height of the detector center and the source above the center of the volume
+    pub detector_vertical_offset: f32,
+    pub source_vertical_offset: f32,
+    // rotation of the detector around its normal (the central ray)
+    pub detector_roll: f32,
+    // rotation of the detector around its horizontal axis
+    pub detector_pitch: f32,
+    // rotation of the detector around its vertical axis
+    pub detector_yaw: f32,
+}
+
+impl Misalignment {
+    // Rotation of the detector in sensor coordinates, where x is horizontal along the
+    // detector, y is the normal of the detector and z is vertical. Roll is applied first, then pitch and yaw.
+    fn detector_rotation(&self) -> [[f32; 3]; 3] {
+        let (sin_roll, cos_roll) = self.detector_roll.sin_cos();
+        let (sin_pitch, cos_pitch) = self.detector_pitch.sin_cos();
+        let (sin_yaw, cos_yaw) = self.detector_yaw.sin_cos();
+
+        let roll = [[cos_roll, 0., sin_roll], [0., 1., 0.], [-sin_roll, 0., cos_roll]];
+        let pitch = [[1., 0., 0.], [0., cos_pitch, -sin_pitch], [0., sin_pitch, cos_pitch]];
+        let yaw = [[cos_yaw, -sin_yaw, 0.], [sin_yaw, cos_yaw, 0.], [0., 0., 1.]];
+
+        mat_mul(yaw, mat_mul(pitch, roll))
+    }
+}
+
+// product of two row-major 3x3 matrices
+fn mat_mul(a: [[f32; 3]; 3], b: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    std::array::from_fn(|row| std::array::from_fn(|col| {
+        (0..3).map(|i| a[row][i] * b[i][col]).sum()
+    }))
 }
 
 impl Projection {
-    pub fn new(world_angle: f32, sod: f32, sdd: f32, detector_dimensions: (f32, f32)) -> Self {
-        
-        let translate = {
-            // distance from the world origin to the sensor center
-            let radius = sdd - sod;
-
-            // the vector that the world will need to be translated by to
-            // move the sensor center to the world origin
-            [
-                -radius * world_angle.cos(),
-                -radius * world_angle.sin(),
-                0., // assume that sensor center and source are at same height
-            ]
-        };
+    pub fn new(
+        world_angle: f32,
+        sod: f32,
+        sdd: f32,
+        detector_dimensions: (f32, f32),
+        misalignment: &Misalignment
+    ) -> Self {
+        // distance from the world origin to the sensor center
+        let radius = sdd - sod;
+        let detector_center = [
+            radius * world_angle.cos(),
+            radius * world_angle.sin(),
+            misalignment.detector_vertical_offset,
+        ];
+
+        // the vector that the world will need to be translated by to
+        // move the sensor center to the world origin
+        let translate = detector_center.map(|coord| -coord);
 
-        let transform = {
+        // rotation from world to sensor-plane coordinates (row-major)
+        let rotation = {
             // the size of the angle between the detector plane and the negative x-axis
             // the world will need to be rotated by this amout to make the plane face
             // towards positive y
             let detector_angle = 3.*PI/2. - world_angle;
 
             // this is a simple 2D rotation matrix leaving the z-coordinate unchanged
-            // this will be applied after the translation in the shader.
-            // WGSL is column-major.
-            [
-                [ detector_angle.cos(), detector_angle.sin(), 0., 0.],
-                [-detector_angle.sin(), detector_angle.cos(), 0., 0.],
-                [0.,                    0.,                   1., 0.],
-            ]
+            let ideal = [
+                [detector_angle.cos(), -detector_angle.sin(), 0.],
+                [detector_angle.sin(),  detector_angle.cos(), 0.],
+                [0.,                    0.,                   1.],
+            ];
+
+            // the rows are the axes of the detector in world coordinates, so rotating the
+            // detector by R in its own coordinates means multiplying by the transpose of R
+            let detector_rotation = misalignment.detector_rotation();
+            let transposed = std::array::from_fn(|row| std::array::from_fn(|col| detector_rotation[col][row]));
+
+            mat_mul(transposed, ideal)
+        };
+
+        // this will be applied after the translation in the shader.
+        // WGSL is column-major.
+        let transform = std::array::from_fn(|col| [rotation[0][col], rotation[1][col], rotation[2][col], 0.]);
+
+        let source = {
+            let source_world = [
+                -sod * world_angle.cos(),
+                -sod * world_angle.sin(),
+                misalignment.source_vertical_offset,
+            ];
+            let relative: [f32; 3] = std::array::from_fn(|i| source_world[i] - detector_center[i]);
+
+            std::array::from_fn(|row| (0..3).map(|col| rotation[row][col] * relative[col]).sum())
         };
 
         // Matrix for taking a point on a projection plane and
         // transforming it to the texture coordinates.
-        // Scales the plane down, flips the y-axis and adds 0.5
+        // Scales the detector down to 0..1, flips the y-axis and adds 0.5
         // to each axis in texture space to align the origins.
+        // The detector offset moves the detector center along the x-axis of the plane.
         // WGSL is column-major.
         let texture_transform = [
-            [0.5/detector_dimensions.0, 0.],
-            [0., -0.5/detector_dimensions.1],
-            [0.5, 0.5]
+            [1./detector_dimensions.0, 0.],
+            [0., -1./detector_dimensions.1],
+            [0.5 - misalignment.detector_offset/detector_dimensions.0, 0.5]
         ];
 
         Self {
             translate,
             transform,
+            source,
 
             texture_transform,
             sdd,
+            sod,
 
             _padding0: 0,
             _padding1: 0,
         }
     }
+
+    // CPU version of project_point in the shaders, the two must be kept in sync
+    pub fn project_point(&self, point_world: [f32; 3]) -> [f32; 3] {
+        let translated = [
+            point_world[0] + self.translate[0],
+            point_world[1] + self.translate[1],
+            point_world[2] + self.translate[2],
+        ];
+
+        // the matrix is column-major, like in WGSL
+        let transformed: [f32; 3] = std::array::from_fn(|row| {
+            (0..3).map(|col| self.transform[col][row] * translated[col]).sum()
+        });
+
+        // intersect the ray from the source through the point with the detector plane
+        let source = self.source;
+        let perspective = source[1] / (source[1] - transformed[1]);
+
+        [
+            source[0] + (transformed[0] - source[0]) * perspective,
+            source[2] + (transformed[2] - source[2]) * perspective,
+            transformed[1],
+        ]
+    }
+
+    // CPU version of projection_to_texture in the shaders, the two must be kept in sync
+    pub fn projection_to_texture(&self, point_proj: [f32; 2]) -> [f32; 2] {
+        let input = [point_proj[0], point_proj[1], 1.];
+
+        std::array::from_fn(|row| {
+            (0..3).map(|col| self.texture_transform[col][row] * input[col]).sum()
+        })
+    }
+
+    // inverse of projection_to_texture
+    pub fn texture_to_projection(&self, point_texture: [f32; 2]) -> [f32; 2] {
+        let [[a, c], [b, d], [tx, ty]] = self.texture_transform;
+        let (x, y) = (point_texture[0] - tx, point_texture[1] - ty);
+
+        let det = a*d - b*c;
+        [(d*x - b*y) / det, (a*y - c*x) / det]
+    }
+
+    // Transform a point from sensor coordinates (before perspective is applied)
+    // back to world coordinates. The transform is a rotation, so its inverse is its transpose.
+    pub fn sensor_to_world(&self, point_sensor: [f32; 3]) -> [f32; 3] {
+        std::array::from_fn(|row| {
+            (0..3).map(|col| self.transform[row][col] * point_sensor[col]).sum::<f32>() - self.translate[row]
+        })
+    }
+
+    // position of the source in world coordinates
+    pub fn source_position(&self) -> [f32; 3] {
+        self.sensor_to_world(self.source)
+    }
+
+    // position of a point on the detector, given in projection coordinates, in world coordinates
+    pub fn detector_to_world(&self, point_proj: [f32; 2]) -> [f32; 3] {
+        self.sensor_to_world([point_proj[0], 0., point_proj[1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOD: f32 = 250.;
+    const SDD: f32 = 320.;
+    const DETECTOR: (f32, f32) = (48., 36.);
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn origin_projects_to_detector_center() {
+        for angle in [0., 0.7, PI, 4.2] {
+            let projection = Projection::new(angle, SOD, SDD, DETECTOR, &Misalignment::default());
+            let point_proj = projection.project_point([0., 0., 0.]);
+            let point_texture = projection.projection_to_texture([point_proj[0], point_proj[1]]);
+
+            assert_close(point_texture[0], 0.5);
+            assert_close(point_texture[1], 0.5);
+        }
+    }
+
+    #[test]
+    fn texture_to_projection_inverts_projection_to_texture() {
+        let misalignment = Misalignment {
+            detector_offset: 1.3,
+            detector_roll: 0.02,
+            ..Default::default()
+        };
+        let projection = Projection::new(1.1, SOD, SDD, DETECTOR, &misalignment);
+
+        for point in [[0., 0.], [10., -5.], [-23.5, 17.2]] {
+            let round_trip = projection.texture_to_projection(projection.projection_to_texture(point));
+
+            assert_close(round_trip[0], point[0]);
+            assert_close(round_trip[1], point[1]);
+        }
+    }
+
+    #[test]
+    fn off_axis_points_are_magnified_by_their_distance_from_the_source() {
+        // at angle 0 the source is on the negative x-axis and the central ray points along x
+        let projection = Projection::new(0., SOD, SDD, DETECTOR, &Misalignment::default());
+
+        for depth in [-20., 0., 20.] {
+            let magnification = SDD / (SOD + depth);
+
+            let vertical = projection.project_point([depth, 0., 5.]);
+            assert_close(vertical[0], 0.);
+            assert_close(vertical[1], 5. * magnification);
+            assert_close(projection.projection_to_texture([vertical[0], vertical[1]])[1], 0.5 - 5. * magnification / DETECTOR.1);
+
+            let horizontal = projection.project_point([depth, 8., 0.]);
+            assert_close(horizontal[0].abs(), 8. * magnification);
+            assert_close(horizontal[1], 0.);
+        }
+    }
+
+    fn limits(max_texture_dimension_2d: u32, max_texture_array_layers: u32) -> wgpu::Limits {
+        wgpu::Limits {
+            max_texture_dimension_2d,
+            max_texture_array_layers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn projection_tiles_fit_within_the_limits() {
+        let tile_size = (100, 80);
+
+        for (limits, n_projections) in [(limits(1024, 256), [1, 256, 257, 720]), (limits(256, 64), [1, 256, 257, 384])] {
+            for n_projections in n_projections {
+                let tiles = ProjectionTiles::new(tile_size, n_projections, &limits).unwrap();
+                let (layer_width, layer_height) = tiles.layer_size();
+
+                assert!(layer_width <= limits.max_texture_dimension_2d && layer_height <= limits.max_texture_dimension_2d);
+                assert!(tiles.layers(n_projections) <= limits.max_texture_array_layers);
+
+                // every projection has its own tile inside a layer
+                let mut origins: Vec<(u32, u32, u32)> = (0..n_projections).map(|index| tiles.origin(index)).collect();
+                for (layer, x, y) in &origins {
+                    assert!(*layer < tiles.layers(n_projections));
+                    assert!(x + tile_size.0 <= layer_width && y + tile_size.1 <= layer_height);
+                }
+                origins.sort();
+                origins.dedup();
+                assert_eq!(origins.len(), n_projections as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn projection_tiles_use_as_few_layers_as_possible() {
+        let tiles = ProjectionTiles::new((100, 80), 1, &limits(1024, 256)).unwrap();
+        assert_eq!((tiles.layers(1), tiles.layer_size(), tiles.origin(0)), (1, (100, 80), (0, 0, 0)));
+
+        let tiles = ProjectionTiles::new((100, 80), 256, &limits(1024, 256)).unwrap();
+        assert_eq!((tiles.layers(256), tiles.layer_size(), tiles.origin(255)), (256, (100, 80), (255, 0, 0)));
+
+        // one more projection than there are layers needs two tiles per layer
+        let tiles = ProjectionTiles::new((100, 80), 257, &limits(1024, 256)).unwrap();
+        assert_eq!((tiles.layers(257), tiles.layer_size(), tiles.origin(3), tiles.origin(256)), (129, (200, 80), (1, 100, 0), (128, 0, 0)));
+
+        let tiles = ProjectionTiles::new((100, 80), 720, &limits(1024, 256)).unwrap();
+        assert_eq!((tiles.layers(720), tiles.layer_size(), tiles.origin(719)), (240, (300, 80), (239, 200, 0)));
+
+        // only two tiles fit next to each other, so the third of each layer goes in the next row
+        let tiles = ProjectionTiles::new((100, 80), 720, &limits(256, 256)).unwrap();
+        assert_eq!((tiles.layers(720), tiles.layer_size(), tiles.origin(6)), (180, (200, 160), (1, 0, 80)));
+    }
+
+    #[test]
+    fn projection_tiles_that_dont_fit_are_rejected() {
+        // 2×3 tiles in each of the 64 layers hold 384 projections
+        assert!(ProjectionTiles::new((100, 80), 385, &limits(256, 64)).is_none());
+        assert!(ProjectionTiles::new((300, 80), 1, &limits(256, 64)).is_none());
+    }
 }
@@ -0,0 +1,129 @@
+struct Projection {
+    translate: vec3<f32>,
+    transform: mat3x3<f32>,
+    source: vec3<f32>, // position of the source in sensor-plane coordinates
+
+    texture_transform: mat3x2<f32>,
+    sdd: f32, // Source to Detector Distance
+    sod: f32, // Source to Object Distance
+}
+
+// each layer of the projections texture holds a grid of projections
+struct ProjectionTiles {
+    tile_size: vec2<u32>, // dimensions of a projection in texels
+    columns: u32,
+    per_layer: u32,
+}
+
+struct Reconstruction {
+    volume_dimensions: vec3<u32>,
+    distance_weighting: u32, // weight samples by 1/U² (FDK)
+    volume_size: f32,
+}
+
+@group(0) @binding(0)
+var projection_textures: texture_2d_array<f32>;
+
+@group(0) @binding(1)
+var<uniform> tiles: ProjectionTiles;
+
+@group(0) @binding(2)
+var<storage, read> projections: array<Projection>;
+
+@group(0) @binding(3)
+var<uniform> reconstruction: Reconstruction;
+
+@group(0) @binding(4)
+var volume: texture_storage_3d<r32float, write>;
+
+// project point in world onto a projection plane as defined by an
+// index in the projections array
+fn project_point(point_world: vec3<f32>, index: u32) -> vec3<f32> {
+    let projection = projections[index];
+
+    let transformed = projection.transform * (point_world + projection.translate);
+
+    // The x and z coordinates of the transformed point corresponds
+    // to the projection plane x and y coordinates. The y coordinate
+    // of the transformed point is the depth, which is used along with
+    // the position of the source to apply perspective.
+    let source = projection.source;
+    let projected = source.xz + (transformed.xz - source.xz) * source.y / (source.y - transformed.y);
+
+    return vec3(projected, transformed.y);
+}
+
+// nearest projection texel at a point in texture coordinates, clamped to the edges
+// of the projection so it doesn't sample the projections next to it
+fn sample_projection(point_texture: vec2<f32>, index: u32) -> f32 {
+    let size = tiles.tile_size;
+    let texel = min(vec2<u32>(max(floor(point_texture * vec2<f32>(size)), vec2(0.))), size - 1u);
+
+    let tile = index % tiles.per_layer;
+    let origin = vec2(tile % tiles.columns, tile / tiles.columns) * size;
+
+    return textureLoad(projection_textures, origin + texel, index / tiles.per_layer, 0).x;
+}
+
+// the axes are scaled separately, so the detector doesn't have to be square
+fn projection_to_texture(point_proj: vec2<f32>, index: u32) -> vec2<f32> {
+    let projection = projections[index];
+
+    return (projection.texture_transform * vec3(point_proj, 1.)).xy;
+}
+
+fn sample_volume(point_world: vec3<f32>, n_projections: u32) -> f32 {
+    var sample_value: f32 = 0.;
+    var hits: u32 = 0;
+    for (var i: u32 = 0; i < n_projections; i++) {
+        let point_proj = project_point(point_world, i);
+        if point_proj.z > 0 {
+            let point_texture = projection_to_texture(point_proj.xy, i);
+
+            // the detector covers 0..1 in texture coordinates
+            if (point_texture.x >= 0. & point_texture.x <= 1. &
+                point_texture.y >= 0. & point_texture.y <= 1.)
+            {
+                // U is the distance from the source to the point along the central
+                // ray, relative to the source-object-distance
+                var weight: f32 = 1.;
+                if reconstruction.distance_weighting != 0 {
+                    let u = (projections[i].source.y - point_proj.z) / projections[i].sod;
+                    weight = 1. / (u*u);
+                }
+
+                sample_value += weight * sample_projection(point_texture, i);
+                hits++;
+            } else {
+                sample_value += 0.;
+            }
+        }
+    }
+
+    if hits == n_projections {
+        return sample_value/f32(n_projections);
+    }
+
+    return 0.;
+    //return sample_value/f32(n_projections);
+}
+
+// position of the center of a voxel in world coordinates
+fn voxel_to_world(voxel: vec3<u32>) -> vec3<f32> {
+    let dimensions = vec3<f32>(reconstruction.volume_dimensions);
+
+    return ((vec3<f32>(voxel) + 0.5) / dimensions - 0.5) * reconstruction.volume_size;
+}
+
+// must match WORKGROUP_SIZE in backprojection.rs
+@compute @workgroup_size(4, 4, 4)
+fn cs_main(@builtin(global_invocation_id) voxel: vec3<u32>) {
+    if any(voxel >= reconstruction.volume_dimensions) {
+        return;
+    }
+
+    let n_projections: u32 = arrayLength(&projections);
+    let value = sample_volume(voxel_to_world(voxel), n_projections);
+
+    textureStore(volume, voxel, vec4(value, 0., 0., 0.));
+}
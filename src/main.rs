@@ -5,6 +5,25 @@ mod ctrs;
 fn main() -> iced::Result {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "--headless") {
+        if let Err(err) = ctrs::headless::run(&args[1..]) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    if args.first().is_some_and(|arg| arg == "--calibrate") {
+        if let Err(err) = ctrs::headless::calibrate(&args[1..]) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     let app = iced::application(
         "CTRS - Rust CT Viewer",
         ctrs::CTRS::update,